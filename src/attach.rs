@@ -0,0 +1,275 @@
+use std::{fmt, time::{Duration, Instant}};
+
+use flume::{Receiver, TryRecvError};
+use gilrs::GamepadId;
+
+use crate::{models::{AttachData, AttachOutcome, AttachResponse, DetachData, GENERIC_PID, GENERIC_VID, TcpMessage}, network::NetworkHandle, protocol::ConfigStatus, reconnect::{Backoff, ReconnectPolicy}};
+
+/// How long an attach request waits for its reply before it counts as failed.
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a gamepad that failed to attach is retried, until it attaches or is unplugged.
+const ATTACH_RETRY: ReconnectPolicy = ReconnectPolicy {
+    initial_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(30),
+    jitter: 0.1,
+    max_attempts: None,
+    max_failures: None
+};
+
+pub enum AttachEvent<Id = GamepadId> {
+    Attached { id: Id, handle: i32, response: AttachResponse },
+    /// The request is sent again after failing.
+    Retrying { id: Id },
+    Failed { id: Id, reason: String, retry_in: Duration }
+}
+
+/// Where a gamepad is in attaching.
+#[derive(Clone)]
+pub enum AttachStatus {
+    /// Waiting for the console to reply.
+    Pending,
+    Attached { device_slot: i16, pad_slot: i8 },
+    /// Detached until it is tried again at `retry_at`.
+    Failed { reason: String, retry_at: Instant }
+}
+
+impl fmt::Display for AttachStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttachStatus::Pending => write!(f, "pending"),
+            AttachStatus::Attached { device_slot, pad_slot } => write!(f, "attached (device {}, pad {})", device_slot, pad_slot),
+            AttachStatus::Failed { reason, retry_at } => write!(f, "failed: {}, retrying in {:.1} s", reason, retry_at.saturating_duration_since(Instant::now()).as_secs_f64())
+        }
+    }
+}
+
+/// A gamepad as listed by `status`.
+pub struct ControllerStatus {
+    pub name: String,
+    pub handle: i32,
+    pub status: AttachStatus
+}
+
+impl fmt::Display for ControllerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (handle {}) {}", self.name, self.handle, self.status)
+    }
+}
+
+enum State {
+    Pending { vid: i16, pid: i16, response: Receiver<AttachOutcome>, deadline: Instant },
+    Waiting { reason: String, retry_at: Instant }
+}
+
+struct Entry<Id> {
+    id: Id,
+    handle: i32,
+    name: String,
+    ids: (i16, i16),
+    state: State,
+    backoff: Backoff
+}
+
+impl<Id: Copy> Entry<Id> {
+    /// Sends the attach request, or schedules a retry when it cannot be sent.
+    fn send(&mut self, (vid, pid): (i16, i16), network: &NetworkHandle, now: Instant) -> Option<AttachEvent<Id>> {
+        let (s, r) = flume::bounded(1);
+        match network.send_tcp(TcpMessage::Attach(AttachData { handle: self.handle, vid, pid, response: s })) {
+            Ok(_) => {
+                self.state = State::Pending { vid, pid, response: r, deadline: now + ATTACH_TIMEOUT };
+                None
+            },
+            Err(e) => Some(self.failed(format!("error on send: {}", e), now))
+        }
+    }
+
+    fn failed(&mut self, reason: String, now: Instant) -> AttachEvent<Id> {
+        // never fails, the policy has no limits
+        let retry_in = self.backoff.failed_attempt().unwrap_or(ATTACH_RETRY.max_delay);
+        self.state = State::Waiting { reason: reason.clone(), retry_at: now + retry_in };
+        AttachEvent::Failed { id: self.id, reason, retry_in }
+    }
+}
+
+/// Gamepads not attached yet: waiting for the console to reply, or for their next try after failing.
+/// The replies are picked up by `poll` without waiting, so input for the controllers already attached keeps flowing meanwhile.
+/// Gamepads are told apart by `Id`, their `GamepadId` unless there is no gilrs, as in tests.
+pub struct AttachQueue<Id = GamepadId> {
+    entries: Vec<Entry<Id>>
+}
+
+impl<Id> Default for AttachQueue<Id> {
+    fn default() -> Self {
+        AttachQueue { entries: Vec::new() }
+    }
+}
+
+impl<Id: Copy + PartialEq> AttachQueue<Id> {
+    /// Asks the console to attach the gamepad, replacing an earlier request for it.
+    /// A device without a config on the console is attached again with the generic IDs.
+    pub fn request(&mut self, id: Id, handle: i32, name: &str, ids: (i16, i16), network: &NetworkHandle) -> Option<AttachEvent<Id>> {
+        self.cancel(id);
+
+        let now = Instant::now();
+        let mut entry = Entry {
+            id,
+            handle,
+            name: name.to_string(),
+            ids,
+            state: State::Waiting { reason: String::new(), retry_at: now },
+            backoff: Backoff::new(ATTACH_RETRY)
+        };
+        let event = entry.send(ids, network, now);
+        self.entries.push(entry);
+        event
+    }
+
+    pub fn contains(&self, id: Id) -> bool {
+        self.entries.iter().any(|entry| entry.id == id)
+    }
+
+    /// Forgets the gamepad, returning its handle. The console may still attach it.
+    pub fn cancel(&mut self, id: Id) -> Option<i32> {
+        let index = self.entries.iter().position(|entry| entry.id == id)?;
+        Some(self.entries.swap_remove(index).handle)
+    }
+
+    pub fn handles(&self) -> impl Iterator<Item = i32> + '_ {
+        self.entries.iter().map(|entry| entry.handle)
+    }
+
+    /// Handles of the requests still waiting for a reply, the console may have attached them.
+    pub fn pending_handles(&self) -> impl Iterator<Item = i32> + '_ {
+        self.entries.iter()
+            .filter(|entry| matches!(entry.state, State::Pending { .. }))
+            .map(|entry| entry.handle)
+    }
+
+    /// Tries the failed gamepads again on the next `poll`, e.g. once the connection is back.
+    pub fn retry_now(&mut self) {
+        let now = Instant::now();
+        for entry in &mut self.entries {
+            if let State::Waiting { retry_at, .. } = &mut entry.state {
+                *retry_at = now;
+            }
+        }
+    }
+
+    pub fn statuses(&self) -> impl Iterator<Item = ControllerStatus> + '_ {
+        self.entries.iter().map(|entry| ControllerStatus {
+            name: entry.name.clone(),
+            handle: entry.handle,
+            status: match &entry.state {
+                State::Pending { .. } => AttachStatus::Pending,
+                State::Waiting { reason, retry_at } => AttachStatus::Failed { reason: reason.clone(), retry_at: *retry_at }
+            }
+        })
+    }
+
+    /// Sends the retries due by `now` and returns what happened to the requests since the last call.
+    pub fn poll(&mut self, network: &NetworkHandle, now: Instant) -> Vec<AttachEvent<Id>> {
+        let mut events = Vec::new();
+
+        self.entries.retain_mut(|entry| {
+            let (vid, pid, outcome) = match &entry.state {
+                State::Waiting { retry_at, .. } if *retry_at <= now => {
+                    events.push(AttachEvent::Retrying { id: entry.id });
+                    events.extend(entry.send(entry.ids, network, now));
+                    return true;
+                },
+                State::Waiting { .. } => return true,
+                State::Pending { vid, pid, response, deadline } => match response.try_recv() {
+                    Err(TryRecvError::Empty) if *deadline > now => return true,
+                    outcome => (*vid, *pid, outcome)
+                }
+            };
+
+            let reason = match outcome {
+                Ok(AttachOutcome::Attached(response)) => {
+                    if let ConfigStatus::NotFound = response.config {
+                        if (vid, pid) != (GENERIC_VID, GENERIC_PID) {
+                            println!("No config for {:04x}:{:04x}, attaching {} with the generic ID instead.", vid, pid, entry.name);
+                            detach(entry.handle, None, network);
+                            entry.ids = (GENERIC_VID, GENERIC_PID);
+                            events.extend(entry.send(entry.ids, network, now));
+                            return true;
+                        }
+                    }
+                    events.push(AttachEvent::Attached { id: entry.id, handle: entry.handle, response });
+                    return false;
+                },
+                Ok(AttachOutcome::InvalidSlot { device_slot, pad_slot }) => format!("invalid slots (device {}, pad {})", device_slot, pad_slot),
+                Ok(AttachOutcome::UnexpectedByte(val)) => format!("unexpected response byte {:#04x}", val),
+                Ok(AttachOutcome::Io(e)) => format!("connection lost: {}", e),
+                Err(TryRecvError::Empty) => "no reply in time".to_string(),
+                Err(TryRecvError::Disconnected) => "the request was dropped".to_string()
+            };
+
+            // whatever the console made of it, nothing stays attached until the retry
+            detach(entry.handle, None, network);
+            events.push(entry.failed(reason, now));
+            true
+        });
+
+        events
+    }
+}
+
+/// The gamepad among `held`, given with its slots, that an attach landed on the slots of.
+/// The console then already knew the handle, likely from another client.
+pub fn collision<Id: Copy>(event: &AttachEvent<Id>, held: impl IntoIterator<Item = (Id, i16, i8)>) -> Option<Id> {
+    let response = match event {
+        AttachEvent::Attached { response, .. } => response,
+        _ => return None
+    };
+    held.into_iter()
+        .find(|&(_, device_slot, pad_slot)| device_slot == response.device_slot && pad_slot == response.pad_slot)
+        .map(|(id, _, _)| id)
+}
+
+/// Asks the console to detach the controller of `handle`, releasing it first on the slots in `release`.
+pub fn detach(handle: i32, release: Option<(i16, i8)>, network: &NetworkHandle) {
+    if let Err(e) = network.send_tcp(TcpMessage::Detach(DetachData { handle, release })) {
+        println!("Unable to detach controller: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{models::AttachResponse, protocol::{ConfigStatus, UserDataStatus}};
+
+    use super::{AttachEvent, collision};
+
+    fn attached(device_slot: i16, pad_slot: i8) -> AttachEvent<usize> {
+        AttachEvent::Attached {
+            id: 3,
+            handle: 30,
+            response: AttachResponse { config: ConfigStatus::Found, user_data: UserDataStatus::Okay, device_slot, pad_slot }
+        }
+    }
+
+    #[test]
+    fn landing_on_held_slots_is_a_collision() {
+        let held = [(1, 0, 0), (2, 1, 0)];
+        assert_eq!(collision(&attached(1, 0), held), Some(2));
+        assert_eq!(collision(&attached(0, 0), held), Some(1));
+    }
+
+    #[test]
+    fn free_slots_are_no_collision() {
+        let held = [(1, 0, 0), (2, 1, 0)];
+        assert_eq!(collision(&attached(2, 0), held), None);
+        assert_eq!(collision(&attached(1, 1), held), None);
+        assert_eq!(collision(&attached(0, 0), []), None);
+    }
+
+    #[test]
+    fn only_attaches_collide() {
+        let held = [(1, 0, 0)];
+        assert_eq!(collision(&AttachEvent::Retrying { id: 3 }, held), None);
+        assert_eq!(collision(&AttachEvent::Failed { id: 3, reason: String::new(), retry_in: Duration::ZERO }, held), None);
+    }
+}
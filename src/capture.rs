@@ -0,0 +1,249 @@
+use std::{fs::File, io::{BufWriter, Write}, net::{IpAddr, SocketAddr}, path::Path, time::SystemTime};
+
+use crate::{models::Direction, trace::Channel};
+
+/// Packets start with their IP header, version 4 or 6.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Writes the traffic with the Wii U to a pcap file, with made up IP, TCP and UDP headers around each message.
+/// Opens in Wireshark without capturing on the network itself.
+pub struct Capture {
+    file: BufWriter<File>,
+    /// Local and remote address of each channel, indexed by `channel_index`.
+    endpoints: [Option<(SocketAddr, SocketAddr)>; 3],
+    /// Bytes sent and received on the control stream, for the TCP sequence numbers.
+    sent: u32,
+    received: u32
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> std::io::Result<Capture> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&0i32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        file.flush()?;
+
+        Ok(Capture {
+            file,
+            endpoints: [None; 3],
+            sent: 0,
+            received: 0
+        })
+    }
+
+    /// Sets the addresses used for the headers of `channel`. A new control stream starts its sequence numbers over.
+    pub fn set_endpoints(&mut self, channel: Channel, local: SocketAddr, remote: SocketAddr) {
+        if let Channel::Control = channel {
+            self.sent = 0;
+            self.received = 0;
+        }
+
+        // the rumble socket listens on every address, show the one the control stream uses
+        let local = match self.endpoints[channel_index(Channel::Control)] {
+            Some((control, _)) if local.ip().is_unspecified() => SocketAddr::new(control.ip(), local.port()),
+            _ => local
+        };
+        self.endpoints[channel_index(channel)] = Some((local, remote));
+    }
+
+    pub fn write(&mut self, channel: Channel, direction: Direction, payload: &[u8]) -> std::io::Result<()> {
+        let (local, remote) = match self.endpoints[channel_index(channel)] {
+            Some(val) => val,
+            // nothing to make the headers from yet
+            None => return Ok(())
+        };
+        let (source, destination) = match direction {
+            Direction::Outgoing => (local, remote),
+            Direction::Incoming => (remote, local)
+        };
+
+        let mut transport = Vec::with_capacity(20 + payload.len());
+        transport.extend_from_slice(&source.port().to_be_bytes());
+        transport.extend_from_slice(&destination.port().to_be_bytes());
+        let protocol = match channel {
+            Channel::Control => {
+                let (sequence, acknowledged) = match direction {
+                    Direction::Outgoing => (&mut self.sent, self.received),
+                    Direction::Incoming => (&mut self.received, self.sent)
+                };
+                transport.extend_from_slice(&sequence.to_be_bytes());
+                transport.extend_from_slice(&acknowledged.to_be_bytes());
+                // 20 byte header, PSH and ACK
+                transport.extend_from_slice(&[5 << 4, 0x18]);
+                transport.extend_from_slice(&0xFFFFu16.to_be_bytes());
+                // checksum and urgent pointer, Wireshark does not check the checksum by default
+                transport.extend_from_slice(&[0; 4]);
+                *sequence = sequence.wrapping_add(payload.len() as u32);
+                PROTOCOL_TCP
+            },
+            Channel::Data | Channel::Rumble => {
+                transport.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+                transport.extend_from_slice(&[0; 2]);
+                PROTOCOL_UDP
+            }
+        };
+        transport.extend_from_slice(payload);
+
+        let mut packet = ip_header(source.ip(), destination.ip(), protocol, transport.len());
+        packet.extend_from_slice(&transport);
+
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        self.file.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        self.file.write_all(&time.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.file.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.file.write_all(&packet)?;
+        // flushed every time, the client is usually stopped by killing it
+        self.file.flush()
+    }
+}
+
+fn channel_index(channel: Channel) -> usize {
+    match channel {
+        Channel::Control => 0,
+        Channel::Data => 1,
+        Channel::Rumble => 2
+    }
+}
+
+fn ip_header(source: IpAddr, destination: IpAddr, protocol: u8, length: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(40 + length);
+    match (source.to_canonical(), destination.to_canonical()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            header.extend_from_slice(&[0x45, 0]);
+            header.extend_from_slice(&((20 + length) as u16).to_be_bytes());
+            // identification, don't fragment, TTL 64
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+
+            let checksum = !header.chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32)
+                .fold(0u32, |sum, val| {
+                    let sum = sum + val;
+                    (sum & 0xFFFF) + (sum >> 16)
+                }) as u16;
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        },
+        _ => {
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&(length as u16).to_be_bytes());
+            header.extend_from_slice(&[protocol, 64]);
+            header.extend_from_slice(&ipv6_octets(source));
+            header.extend_from_slice(&ipv6_octets(destination));
+        }
+    }
+    header
+}
+
+fn ipv6_octets(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped().octets(),
+        IpAddr::V6(address) => address.octets()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryInto, fs, net::SocketAddr};
+
+    use super::{Capture, LINKTYPE_RAW, PROTOCOL_TCP, PROTOCOL_UDP, SNAPLEN};
+    use crate::{models::Direction, trace::Channel};
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn le_u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// The packets of a pcap file, after checking its global header and record lengths.
+    fn packets(file: &[u8]) -> Vec<&[u8]> {
+        assert_eq!(le_u32_at(file, 0), 0xa1b2c3d4);
+        assert_eq!(&file[4..8], &[2, 0, 4, 0]);
+        assert_eq!(le_u32_at(file, 16), SNAPLEN);
+        assert_eq!(le_u32_at(file, 20), LINKTYPE_RAW);
+
+        let mut packets = Vec::new();
+        let mut offset = 24;
+        while offset < file.len() {
+            let included = le_u32_at(file, offset + 8) as usize;
+            assert_eq!(le_u32_at(file, offset + 12) as usize, included);
+            packets.push(&file[offset + 16..offset + 16 + included]);
+            offset += 16 + included;
+        }
+        assert_eq!(offset, file.len());
+        packets
+    }
+
+    /// Ones' complement sum over an IPv4 header, 0xFFFF when its checksum is right.
+    fn header_sum(header: &[u8]) -> u16 {
+        let sum = header.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32).sum::<u32>();
+        ((sum & 0xFFFF) + (sum >> 16)) as u16
+    }
+
+    #[test]
+    fn writes_readable_packets() {
+        let path = std::env::temp_dir().join(format!("network-client-capture-{}.pcap", std::process::id()));
+        let local: SocketAddr = "192.168.1.2:50000".parse().unwrap();
+        let console: SocketAddr = "192.168.1.10:8112".parse().unwrap();
+
+        let mut capture = Capture::create(&path).unwrap();
+        // nothing is written before the channel has addresses
+        capture.write(Channel::Data, Direction::Outgoing, &[1]).unwrap();
+        capture.set_endpoints(Channel::Control, local, console);
+        capture.set_endpoints(Channel::Data, "192.168.1.2:8113".parse().unwrap(), "192.168.1.10:8113".parse().unwrap());
+        capture.set_endpoints(Channel::Rumble, "[::1]:8114".parse().unwrap(), "[::1]:9000".parse().unwrap());
+        capture.write(Channel::Control, Direction::Outgoing, &[1, 2, 3]).unwrap();
+        capture.write(Channel::Control, Direction::Incoming, &[4, 5, 6, 7, 8]).unwrap();
+        capture.write(Channel::Control, Direction::Outgoing, &[9, 10]).unwrap();
+        capture.write(Channel::Data, Direction::Outgoing, &[0; 10]).unwrap();
+        capture.write(Channel::Rumble, Direction::Incoming, &[1, 0, 0, 0, 1, 1]).unwrap();
+        drop(capture);
+
+        let file = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let packets = packets(&file);
+        assert_eq!(packets.len(), 5);
+
+        // IPv4 and TCP, sequence numbers count the bytes each side sent before
+        for (packet, payload, source_port, sequence, acknowledged) in [(packets[0], 3, 50000, 0, 0), (packets[1], 5, 8112, 0, 3), (packets[2], 2, 50000, 3, 5)] {
+            assert_eq!(packet.len(), 20 + 20 + payload);
+            assert_eq!(packet[0], 0x45);
+            assert_eq!(u16_at(packet, 2) as usize, packet.len());
+            assert_eq!(packet[9], PROTOCOL_TCP);
+            assert_eq!(header_sum(&packet[..20]), 0xFFFF);
+            assert_eq!(u16_at(packet, 20), source_port);
+            assert_eq!((u32_at(packet, 24), u32_at(packet, 28)), (sequence, acknowledged));
+        }
+        assert_eq!(&packets[1][12..16], &[192, 168, 1, 10]);
+
+        // IPv4 and UDP
+        let data = packets[3];
+        assert_eq!(data.len(), 20 + 8 + 10);
+        assert_eq!(data[9], PROTOCOL_UDP);
+        assert_eq!(header_sum(&data[..20]), 0xFFFF);
+        assert_eq!(u16_at(data, 24), 8 + 10);
+
+        // IPv6 and UDP
+        let rumble = packets[4];
+        assert_eq!(rumble.len(), 40 + 8 + 6);
+        assert_eq!(rumble[0] >> 4, 6);
+        assert_eq!(u16_at(rumble, 4), 8 + 6);
+        assert_eq!(rumble[6], PROTOCOL_UDP);
+        assert_eq!((u16_at(rumble, 40), u16_at(rumble, 42)), (9000, 8114));
+    }
+}
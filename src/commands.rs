@@ -1,8 +1,4 @@
-use std::io::Write;
-
-use bytebuffer::ByteBuffer;
-
-use crate::models::{Controller, TcpProtocol, UdpProtocol};
+use crate::{controller_manager::REPORT_SIZE, models::Controller, protocol::{AttachRequest, DataPacket, DetachRequest, EncodeError, Message, Ping}};
 
 pub trait Command : Send {
     fn data(&self) -> String;
@@ -19,18 +15,15 @@ pub struct AttachCommand {
 
 impl AttachCommand {
     pub fn new(handle: i32, vid: i16, pid: i16, sender: i32) -> AttachCommand {
-        let mut buffer = ByteBuffer::new();
-        buffer.write_u8(TcpProtocol::TcpCommandAttach.into());
-        buffer.write_i32(handle);
-        buffer.write_i16(vid);
-        buffer.write_i16(pid);
+        let mut data = Vec::new();
+        AttachRequest { handle, vid, pid }.encode(&mut data);
 
         AttachCommand {
             handle,
             vid,
             pid,
             sender,
-            data: buffer.to_bytes()
+            data
         }
     }
 }
@@ -53,14 +46,13 @@ pub struct DetachCommand {
 
 impl DetachCommand {
     pub fn new(handle: i32, sender: i32) -> DetachCommand {
-        let mut buffer = ByteBuffer::new();
-        buffer.write_u8(TcpProtocol::TcpCommandDetach.into());
-        buffer.write_i32(handle);
+        let mut data = Vec::new();
+        DetachRequest { handle }.encode(&mut data);
 
         DetachCommand {
             handle,
             sender,
-            data: buffer.to_bytes()
+            data
         }
     }
 }
@@ -75,41 +67,86 @@ impl Command for DetachCommand {
     }
 }
 
-pub struct WriteCommand {
-    sender: i32,
-    data: Vec<u8>
+/// Controller input of one tick, split into datagrams that each fit the MTU.
+#[derive(Default)]
+pub struct Frame {
+    data: Vec<u8>,
+    /// Where each datagram in `data` starts.
+    starts: Vec<usize>,
+    /// Counts up whenever the input changed.
+    pub change: u64,
+    /// Which redundant copy of the change this is, 0 for the frame itself.
+    pub copy: u32
 }
 
-impl WriteCommand {
-    pub fn new(controllers: &Vec<(&Controller, Vec<u8>)>, sender: i32) -> WriteCommand {
-        let mut buffer = ByteBuffer::new();
-        buffer.write_u8(UdpProtocol::UdpCommandData.into());
-        if controllers.len() > 0xFF {
-            println!("Too many controllers!");
-        }
-        buffer.write_u8(controllers.len() as u8);
-        for controller in controllers {
-            buffer.write_i32(controller.0.handle);
-            buffer.write_i16(controller.0.device_slot);
-            buffer.write_i8(controller.0.pad_slot);
-            buffer.write_i8((controller.1.len() & 0xFF) as i8);
-            buffer.write_all(&controller.1).unwrap();
-        }
+impl Frame {
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.starts.clear();
+    }
 
-        WriteCommand {
-            sender,
-            data: buffer.to_bytes()
-        }
+    /// Makes this frame the same as `other`, reusing its buffers.
+    pub fn copy_from(&mut self, other: &Frame) {
+        self.data.clear();
+        self.data.extend_from_slice(&other.data);
+        self.starts.clear();
+        self.starts.extend_from_slice(&other.starts);
+        self.change = other.change;
+        self.copy = other.copy;
+    }
+
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        let ends = self.starts.iter().skip(1).copied().chain(std::iter::once(self.data.len()));
+        self.starts.iter().zip(ends).map(move |(&start, end)| &self.data[start..end])
     }
 }
 
-impl Command for WriteCommand {
-    fn data(&self) -> String {
-        format!("WriteCommand [sender={}]", self.sender)
+/// Input of one controller and where it goes on the console.
+#[derive(Clone, Copy)]
+pub struct ControllerReport {
+    pub handle: i32,
+    pub device_slot: i16,
+    pub pad_slot: i8,
+    pub report: [u8; REPORT_SIZE]
+}
+
+impl ControllerReport {
+    pub fn new(controller: &Controller, report: [u8; REPORT_SIZE]) -> ControllerReport {
+        ControllerReport { handle: controller.handle, device_slot: controller.device_slot, pad_slot: controller.pad_slot, report }
     }
+}
 
-    fn byte_data(&self) -> &Vec<u8> {
-        &self.data
+/// Encodes the input of every controller into a reused frame, so sending it does not allocate.
+pub struct WriteCommand;
+
+impl WriteCommand {
+    /// Starts a new datagram whenever the next controller would not fit into `max_datagram` bytes.
+    pub fn encode(controllers: impl Iterator<Item = ControllerReport>, max_datagram: usize, frame: &mut Frame) -> Result<(), EncodeError> {
+        let mut count = 0;
+        for controller in controllers {
+            let size = DataPacket::entry_size(&controller.report)?;
+            if DataPacket::HEADER_SIZE + size > max_datagram {
+                return Err(EncodeError::ExceedsMtu { size: DataPacket::HEADER_SIZE + size, max: max_datagram });
+            }
+
+            let full = match frame.starts.last() {
+                Some(&start) => count == DataPacket::MAX_ENTRIES || frame.data.len() - start + size > max_datagram,
+                None => true
+            };
+            if full {
+                frame.starts.push(frame.data.len());
+                DataPacket::write_header(0, &mut frame.data);
+                count = 0;
+            }
+
+            DataPacket::write_entry(controller.handle, controller.device_slot, controller.pad_slot, &controller.report, &mut frame.data)?;
+            count += 1;
+
+            let start = *frame.starts.last().unwrap();
+            DataPacket::set_count(&mut frame.data[start..], count as u8);
+        }
+
+        Ok(())
     }
 }
 
@@ -119,12 +156,21 @@ pub struct PingCommand {
 
 impl PingCommand {
     pub fn new() -> PingCommand {
+        let mut data = Vec::new();
+        Ping.encode(&mut data);
+
         PingCommand {
-            data: vec![TcpProtocol::TcpCommandPing.into()]
+            data
         }
     }
 }
 
+impl Default for PingCommand {
+    fn default() -> Self {
+        PingCommand::new()
+    }
+}
+
 impl Command for PingCommand {
     fn data(&self) -> String {
         "PingCommand []".to_owned()
@@ -133,4 +179,88 @@ impl Command for PingCommand {
     fn byte_data(&self) -> &Vec<u8> {
         &self.data
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::{ControllerReport, Frame, WriteCommand};
+    use crate::{controller_manager::REPORT_SIZE, protocol::{DataEntry, DataPacket, EncodeError}};
+
+    /// Size of one encoded controller.
+    const ENTRY: usize = 8 + REPORT_SIZE;
+
+    fn reports(count: usize) -> Vec<ControllerReport> {
+        (0..count).map(|index| ControllerReport {
+            handle: index as i32 + 1,
+            device_slot: index as i16,
+            pad_slot: (index % 4) as i8,
+            report: [index as u8; REPORT_SIZE]
+        }).collect()
+    }
+
+    /// Encodes `reports` and decodes every datagram again, checking each is no larger than `max_datagram`.
+    /// Returns the number of entries per datagram.
+    fn encode(reports: &[ControllerReport], max_datagram: usize) -> Vec<usize> {
+        let mut frame = Frame::default();
+        WriteCommand::encode(reports.iter().copied(), max_datagram, &mut frame).unwrap();
+
+        let mut entries = Vec::new();
+        let mut counts = Vec::new();
+        for datagram in frame.datagrams() {
+            assert!(datagram.len() <= max_datagram, "{} byte datagram, at most {} allowed", datagram.len(), max_datagram);
+            let (packet, size) = DataPacket::decode(datagram).unwrap();
+            assert_eq!(size, datagram.len());
+            counts.push(packet.entries.len());
+            entries.extend(packet.entries);
+        }
+
+        let expected: Vec<DataEntry> = reports.iter()
+            .map(|report| DataEntry { handle: report.handle, device_slot: report.device_slot, pad_slot: report.pad_slot, data: report.report.to_vec() })
+            .collect();
+        assert_eq!(entries, expected);
+        counts
+    }
+
+    #[test]
+    fn no_controllers_no_datagrams() {
+        assert!(encode(&[], 1400).is_empty());
+    }
+
+    #[test]
+    fn everything_fits_one_datagram() {
+        assert_eq!(encode(&reports(4), 1400), [4]);
+    }
+
+    #[test]
+    fn splits_at_the_mtu() {
+        // exactly three entries fit
+        let max_datagram = DataPacket::HEADER_SIZE + 3 * ENTRY;
+        assert_eq!(encode(&reports(7), max_datagram), [3, 3, 1]);
+        assert_eq!(encode(&reports(7), max_datagram - 1), [2, 2, 2, 1]);
+        assert_eq!(encode(&reports(2), DataPacket::HEADER_SIZE + ENTRY), [1, 1]);
+    }
+
+    #[test]
+    fn splits_at_the_entry_limit() {
+        assert_eq!(encode(&reports(600), usize::MAX), [255, 255, 90]);
+    }
+
+    #[test]
+    fn rejects_a_controller_larger_than_the_mtu() {
+        let mut frame = Frame::default();
+        let max_datagram = DataPacket::HEADER_SIZE + ENTRY - 1;
+        assert_eq!(WriteCommand::encode(reports(1).into_iter(), max_datagram, &mut frame),
+            Err(EncodeError::ExceedsMtu { size: DataPacket::HEADER_SIZE + ENTRY, max: max_datagram }));
+    }
+
+    #[test]
+    fn reused_frames_start_over() {
+        let mut frame = Frame::default();
+        WriteCommand::encode(reports(5).into_iter(), 1400, &mut frame).unwrap();
+        frame.clear();
+        WriteCommand::encode(reports(2).into_iter(), 1400, &mut frame).unwrap();
+
+        let datagrams: Vec<&[u8]> = frame.datagrams().collect();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(DataPacket::decode(datagrams[0]).unwrap().0.entries.len(), 2);
+    }
+}
@@ -1,8 +1,8 @@
 use std::slice::Iter;
 
-use bytebuffer::ByteBuffer;
 use gilrs::{Axis, Button, Gamepad};
 
+#[allow(dead_code)]
 struct BinaryPrint(Vec<u8>);
 
 impl std::fmt::Binary for BinaryPrint {
@@ -17,6 +17,10 @@ impl std::fmt::Binary for BinaryPrint {
     }
 }
 
+/// Size of the input report `poll` returns for every controller.
+pub const REPORT_SIZE: usize = 8;
+
+#[derive(Default)]
 pub struct ControllerManager {}
 
 impl ControllerManager {
@@ -24,16 +28,26 @@ impl ControllerManager {
         ControllerManager {}
     }
 
-    pub fn poll(&self, gamepad: &Gamepad) -> Vec<u8> {
+    pub fn poll(&self, gamepad: &Gamepad) -> [u8; REPORT_SIZE] {
         let (buttons_state, stick_state) =
             self.fetch(gamepad);
 
-        let mut data = ByteBuffer::new();
+        let mut data = [0; REPORT_SIZE];
+        data[..4].copy_from_slice(&stick_state.to_be_bytes());
+        data[4..].copy_from_slice(&buttons_state.to_be_bytes());
+
+        data
+    }
 
-        data.write_i32(stick_state);
-        data.write_i32(buttons_state);
+    /// Report of a controller with nothing pressed and both sticks centered.
+    pub fn neutral() -> [u8; REPORT_SIZE] {
+        let stick_state = ControllerManager::axes_iterator()
+            .map(|&axis| ControllerManager::map_axis_data(0.0, axis))
+            .fold(0, |accumulated, element| accumulated | element);
 
-        data.to_bytes()
+        let mut data = [0; REPORT_SIZE];
+        data[..4].copy_from_slice(&stick_state.to_be_bytes());
+        data
     }
 
     fn fetch(&self, gamepad: &Gamepad) -> (i32, i32) {
@@ -64,7 +78,7 @@ impl ControllerManager {
             .map(|&button| ControllerManager::map_trigger_button_data(button))
             .fold(trigger_state, |accumulated, element| accumulated | element);
 
-        buttons_state = buttons_state | self.overflow(trigger_state as i32);
+        buttons_state |= self.overflow(trigger_state as i32);
 
         (buttons_state, stick_state)
     }
@@ -84,7 +98,7 @@ impl ControllerManager {
                 Button::LeftThumb, Button::RightThumb,
                 Button::Mode
             ];
-        return BUTTONS.iter();
+        BUTTONS.iter()
     }
 
     fn axes_iterator() -> Iter<'static, Axis> {
@@ -93,7 +107,7 @@ impl ControllerManager {
                 Axis::LeftStickX, Axis::LeftStickY,
                 Axis::RightStickX, Axis::RightStickY
             ];
-        return AXES.iter();
+        AXES.iter()
     }
     
     fn triggers_iterator() -> Iter<'static, Axis> {
@@ -101,7 +115,7 @@ impl ControllerManager {
         [
             Axis::LeftZ, Axis::RightZ
         ];
-        return TRIGGERS.iter();
+        TRIGGERS.iter()
     }
 
     fn triggers_buttons_iterator() -> Iter<'static, Button> {
@@ -109,7 +123,7 @@ impl ControllerManager {
         [
             Button::LeftTrigger2, Button::RightTrigger2
         ];
-        return TRIGGER.iter();
+        TRIGGER.iter()
     }
 
     fn map_button_state(button: Button) -> i32 {
@@ -145,6 +159,7 @@ impl ControllerManager {
         }
     }
     
+    #[allow(clippy::identity_op)]
     fn map_axis_data(value: f32, axis: Axis) -> i32 {
         let result = (((value * 128.0) + 128.0) as u8) as i32;
     
@@ -157,6 +172,7 @@ impl ControllerManager {
         }
     }
     
+    #[allow(clippy::identity_op)]
     fn map_trigger_data(value: f32, trigger: Axis) -> i16 {
         let result = (((value * 128.0) + 128.0) as u8) as i16;
         match trigger {
@@ -166,6 +182,7 @@ impl ControllerManager {
         }
     }
 
+    #[allow(clippy::identity_op)]
     fn map_trigger_button_data(trigger_button: Button) -> i16 {
         match trigger_button {
             Button::LeftTrigger2 => 128 << 8,
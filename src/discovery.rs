@@ -0,0 +1,118 @@
+use std::{fmt, net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket}, str::FromStr, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU32, Ordering}}, thread, time::Duration};
+
+use crate::{models::ProtocolVersion, network};
+
+const SCAN_THREADS: u32 = 64;
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// An IPv4 network in CIDR notation, a single address is read as /32.
+#[derive(Copy, Clone)]
+pub struct Subnet {
+    base: u32,
+    prefix: u8
+}
+
+impl Subnet {
+    /// The /24 around the address used to reach the default route, usually the LAN the Wii U is on.
+    pub fn local() -> Option<Subnet> {
+        let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+        // nothing is sent, connecting only picks the outgoing interface
+        socket.connect("192.0.2.1:9").ok()?;
+        match socket.local_addr().ok()?.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(Subnet::new(ip, 24)),
+            _ => None
+        }
+    }
+
+    fn new(ip: Ipv4Addr, prefix: u8) -> Subnet {
+        let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+        Subnet { base: u32::from(ip) & mask, prefix }
+    }
+
+    /// Every usable host address, network and broadcast addresses are skipped when there are any.
+    fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let size = 1u64 << (32 - self.prefix);
+        let (first, last) = if size > 2 { (1, size - 2) } else { (0, size - 1) };
+        let base = self.base as u64;
+        (first..=last).map(move |offset| Ipv4Addr::from((base + offset) as u32))
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match val.split_once('/') {
+            Some((ip, prefix)) => (ip, prefix.parse::<u8>().map_err(|e| format!("Unable to parse prefix length: {}", e))?),
+            None => (val, 32)
+        };
+
+        if prefix > 32 {
+            return Err("Prefix length must be at most 32".to_owned());
+        }
+
+        // keeps a typo from scanning the whole internet
+        if prefix < 16 {
+            return Err("Refusing to scan networks larger than /16".to_owned());
+        }
+
+        let ip = ip.parse::<Ipv4Addr>().map_err(|e| format!("Unable to parse network address: {}", e))?;
+        Ok(Subnet::new(ip, prefix))
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.base), self.prefix)
+    }
+}
+
+pub struct Candidate {
+    pub ip: IpAddr,
+    pub version: ProtocolVersion
+}
+
+/// Looks for hosts accepting the HIDtoVPAD handshake on `port` in `subnet`.
+/// With `first_only` the scan stops at the first server found.
+pub fn scan(subnet: Subnet, port: u16, first_only: bool) -> Vec<Candidate> {
+    println!("[Discovery] Scanning {}", subnet);
+
+    let hosts: Vec<Ipv4Addr> = subnet.hosts().collect();
+    let hosts = Arc::new(hosts);
+    let next = Arc::new(AtomicU32::new(0));
+    let found = Arc::new(AtomicBool::new(false));
+    let candidates = Arc::new(Mutex::new(Vec::new()));
+
+    let workers: Vec<_> = (0..SCAN_THREADS.min(hosts.len() as u32)).map(|_| {
+        let hosts = hosts.clone();
+        let next = next.clone();
+        let found = found.clone();
+        let candidates = candidates.clone();
+        thread::spawn(move || {
+            loop {
+                if first_only && found.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let ip = match hosts.get(next.fetch_add(1, Ordering::Relaxed) as usize) {
+                    Some(&ip) => IpAddr::V4(ip),
+                    None => return
+                };
+
+                if let Some(version) = network::probe(SocketAddr::new(ip, port), PROBE_TIMEOUT) {
+                    println!("[Discovery] Found HIDtoVPAD ({:?}) at {}", version, ip);
+                    found.store(true, Ordering::Relaxed);
+                    candidates.lock().unwrap().push(Candidate { ip, version });
+                }
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    let mut candidates = std::mem::take(&mut *candidates.lock().unwrap());
+    candidates.sort_by_key(|candidate| candidate.ip);
+    candidates
+}
@@ -0,0 +1,370 @@
+use std::{collections::HashMap, io::{ErrorKind, Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}, time::Duration};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use crate::{models::ProtocolVersion, protocol::{AttachReply, ConfigStatus, ControlRequest, DataPacket, DecodeError, Message, Pong, RumblePacket, UserDataStatus}};
+
+/// Where the emulated Wii U stops answering, to see how the client copes with a hung console.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Unresponsive {
+    /// Accepts connections but never offers a protocol version.
+    Handshake,
+    /// Completes the handshake, then never reads or answers another request.
+    Requests
+}
+
+/// How often a thread serving a client checks whether the emulator is stopping.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Behaviour of the emulated Wii U side.
+pub struct EmulatorConfig {
+    pub version: ProtocolVersion,
+    pub config_found: bool,
+    pub user_data_okay: bool,
+    pub device_slot: i16,
+    pub pad_slot: i8,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+    /// Port of the client that rumble is sent to.
+    pub rumble_port: u16,
+    pub unresponsive: Option<Unresponsive>
+}
+
+/// A device attached to the emulated Wii U.
+#[derive(Clone, Debug)]
+pub struct AttachedDevice {
+    pub handle: i32,
+    pub device_slot: i16,
+    pub pad_slot: i8,
+    pub vid: i16,
+    pub pid: i16,
+    /// Latest controller data received for it.
+    pub last_data: Vec<u8>
+}
+
+#[derive(Default)]
+struct Session {
+    client: Option<IpAddr>,
+    devices: HashMap<i32, AttachedDevice>,
+    /// Controller data packets received, valid or not.
+    packets: u64,
+    /// Latest data received for every handle, attached or not, kept when its client is gone.
+    last_data: HashMap<i32, Vec<u8>>
+}
+
+/// A running emulator. It serves clients on its own threads until stopped.
+pub struct Emulator {
+    session: Arc<Mutex<Session>>,
+    rumble_socket: UdpSocket,
+    rumble_port: u16,
+    tcp_port: u16,
+    udp_port: u16,
+    stopping: Arc<AtomicBool>
+}
+
+/// Acts like HIDtoVPAD running on a Wii U, so the client can be run without a console.
+/// Port 0 picks a free port, see `tcp_port` and `udp_port`.
+pub fn start(config: EmulatorConfig) -> std::io::Result<Emulator> {
+    // [::] also accepts IPv4 clients where dual-stack sockets are the default
+    let tcp_listener = TcpListener::bind(("::", config.tcp_port)).or_else(|_| TcpListener::bind(("0.0.0.0", config.tcp_port)))?;
+    let udp_socket = UdpSocket::bind(("::", config.udp_port)).or_else(|_| UdpSocket::bind(("0.0.0.0", config.udp_port)))?;
+    let rumble_socket = UdpSocket::bind("[::]:0").or_else(|_| UdpSocket::bind("0.0.0.0:0"))?;
+
+    let emulator = Emulator {
+        session: Arc::new(Mutex::new(Session::default())),
+        rumble_socket,
+        rumble_port: config.rumble_port,
+        tcp_port: tcp_listener.local_addr()?.port(),
+        udp_port: udp_socket.local_addr()?.port(),
+        stopping: Arc::new(AtomicBool::new(false))
+    };
+
+    println!("[Emulator] Offering {:?}, listening on TCP {} and UDP {}", config.version, emulator.tcp_port, emulator.udp_port);
+
+    start_control_thread(tcp_listener, config, emulator.session.clone(), emulator.stopping.clone());
+    start_data_thread(udp_socket, emulator.session.clone(), emulator.stopping.clone());
+    Ok(emulator)
+}
+
+impl Emulator {
+    pub fn tcp_port(&self) -> u16 {
+        self.tcp_port
+    }
+
+    pub fn udp_port(&self) -> u16 {
+        self.udp_port
+    }
+
+    /// Address of the client being served.
+    pub fn client(&self) -> Option<IpAddr> {
+        self.session.lock().unwrap().client
+    }
+
+    /// Devices attached by the current client, by handle.
+    pub fn devices(&self) -> Vec<AttachedDevice> {
+        let mut devices: Vec<AttachedDevice> = self.session.lock().unwrap().devices.values().cloned().collect();
+        devices.sort_by_key(|device| device.handle);
+        devices
+    }
+
+    /// Latest controller data received for `handle`, also from a client that has left since.
+    pub fn last_data(&self, handle: i32) -> Option<Vec<u8>> {
+        self.session.lock().unwrap().last_data.get(&handle).cloned()
+    }
+
+    /// Controller data packets received so far.
+    pub fn packets(&self) -> u64 {
+        self.session.lock().unwrap().packets
+    }
+
+    /// Starts or stops rumble for `handle` on the current client.
+    pub fn rumble(&self, handle: i32, start: bool) {
+        let client = match self.client() {
+            Some(val) => val,
+            None => {
+                println!("[Emulator] No client connected");
+                return;
+            }
+        };
+
+        let mut buffer = Vec::new();
+        RumblePacket { handle, active: start }.encode(&mut buffer);
+
+        match self.rumble_socket.send_to(&buffer, SocketAddr::new(client, self.rumble_port)) {
+            Ok(_) => println!("[Emulator] Rumble {} for handle {}", if start { "started" } else { "stopped" }, handle),
+            Err(e) => println!("[Emulator] Unable to send rumble: {}", e)
+        }
+    }
+
+    /// Stops accepting clients and data, the client being served is dropped.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        // wakes up the threads blocked on accept and receive
+        let _ = TcpStream::connect_timeout(&SocketAddr::new(Ipv6Addr::LOCALHOST.into(), self.tcp_port), POLL_INTERVAL)
+            .or_else(|_| TcpStream::connect_timeout(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.tcp_port), POLL_INTERVAL));
+        let _ = self.rumble_socket.send_to(&[], SocketAddr::new(Ipv6Addr::LOCALHOST.into(), self.udp_port))
+            .or_else(|_| UdpSocket::bind("0.0.0.0:0").and_then(|socket| socket.send_to(&[], SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.udp_port))));
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn start_control_thread(listener: TcpListener, config: EmulatorConfig, session: Arc<Mutex<Session>>, stopping: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        // HIDtoVPAD only serves a single client at a time
+        for stream in listener.incoming() {
+            if stopping.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let mut stream = match stream {
+                Ok(val) => val,
+                Err(e) => {
+                    println!("[Emulator] Unable to accept client: {}", e);
+                    continue;
+                }
+            };
+
+            let peer = stream.peer_addr().ok();
+            println!("[Emulator] Client connected: {:?}", peer);
+
+            if config.unresponsive == Some(Unresponsive::Handshake) {
+                ignore_client(stream);
+                continue;
+            }
+
+            let version = match handshake(&mut stream, config.version) {
+                Some(val) => val,
+                None => {
+                    println!("[Emulator] Handshake failed");
+                    continue;
+                }
+            };
+            println!("[Emulator] Client speaks {:?}", version);
+
+            if config.unresponsive == Some(Unresponsive::Requests) {
+                hold_client(stream, stopping.clone());
+                continue;
+            }
+
+            {
+                let mut session = session.lock().unwrap();
+                session.client = peer.map(|addr| addr.ip());
+                session.devices.clear();
+            }
+
+            if let Err(e) = serve_client(&mut stream, &config, &session, &stopping) {
+                println!("[Emulator] Client gone: {}", e);
+            }
+
+            session.lock().unwrap().client = None;
+        }
+    })
+}
+
+/// Keeps reading from the client until it leaves, without ever answering.
+fn ignore_client(mut stream: TcpStream) {
+    println!("[Emulator] Not answering this client");
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        while let Ok(count) = stream.read(&mut buffer) {
+            if count == 0 {
+                break;
+            }
+            println!("[Emulator] Ignored {:02x?}", &buffer[..count]);
+        }
+        println!("[Emulator] Ignored client gone");
+    });
+}
+
+/// Keeps the connection open without reading from it until the emulator stops, so whatever the client sends piles up.
+fn hold_client(stream: TcpStream, stopping: Arc<AtomicBool>) {
+    println!("[Emulator] Not reading from this client");
+    thread::spawn(move || {
+        while !stopping.load(Ordering::Relaxed) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        drop(stream);
+    });
+}
+
+fn handshake(stream: &mut TcpStream, offered: ProtocolVersion) -> Option<ProtocolVersion> {
+    stream.write_u8(offered.into()).ok()?;
+
+    let requested = stream.read_u8().ok()?;
+    let answer = match ProtocolVersion::from(requested) {
+        ProtocolVersion::Abort | ProtocolVersion::Unknown => {
+            println!("[Emulator] Client aborted the handshake ({:#04x})", requested);
+            return None;
+        },
+        version if (version as u8) <= (offered as u8) => version,
+        _ => ProtocolVersion::Abort
+    };
+
+    stream.write_u8(answer.into()).ok()?;
+    match answer {
+        ProtocolVersion::Abort => None,
+        version => Some(version)
+    }
+}
+
+fn serve_client(stream: &mut TcpStream, config: &EmulatorConfig, session: &Mutex<Session>, stopping: &AtomicBool) -> std::io::Result<()> {
+    let mut pending = Vec::new();
+    let mut read_buffer = [0; 256];
+    let mut response = Vec::new();
+    // wakes up now and then to see whether the emulator is stopping
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    loop {
+        if stopping.load(Ordering::Relaxed) {
+            return Err(std::io::Error::new(ErrorKind::Interrupted, "emulator stopped"));
+        }
+
+        let count = match stream.read(&mut read_buffer) {
+            Ok(val) => val,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e)
+        };
+        if count == 0 {
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        pending.extend_from_slice(&read_buffer[..count]);
+
+        loop {
+            let (request, size) = match ControlRequest::decode(&pending) {
+                Ok(val) => val,
+                Err(DecodeError::Incomplete { .. }) => break,
+                Err(e) => return Err(std::io::Error::new(ErrorKind::InvalidData, e))
+            };
+            pending.drain(..size);
+
+            response.clear();
+            match request {
+                ControlRequest::Attach(request) => {
+                    let (device_slot, pad_slot) = {
+                        let mut session = session.lock().unwrap();
+                        // the lowest slot no other device is on, slots of detached devices are handed out again
+                        let device_slot = (config.device_slot..).find(|slot| !session.devices.values().any(|device| device.device_slot == *slot)).unwrap();
+                        let device = session.devices.entry(request.handle).or_insert(AttachedDevice {
+                            handle: request.handle,
+                            device_slot,
+                            pad_slot: config.pad_slot,
+                            vid: request.vid,
+                            pid: request.pid,
+                            last_data: Vec::new()
+                        });
+                        (device.device_slot, device.pad_slot)
+                    };
+
+                    println!("[Emulator] Attach handle={} vid={:#06x} pid={:#06x} -> device_slot={} pad_slot={}",
+                        request.handle, request.vid, request.pid, device_slot, pad_slot);
+
+                    AttachReply {
+                        config: if config.config_found { ConfigStatus::Found } else { ConfigStatus::NotFound },
+                        user_data: if config.user_data_okay { UserDataStatus::Okay } else { UserDataStatus::Bad },
+                        device_slot,
+                        pad_slot
+                    }.encode(&mut response);
+                },
+                ControlRequest::Detach(request) => {
+                    println!("[Emulator] Detach handle={}", request.handle);
+                    session.lock().unwrap().devices.remove(&request.handle);
+                },
+                ControlRequest::Ping => Pong.encode(&mut response),
+                ControlRequest::Abort => {
+                    println!("[Emulator] Client closed the connection");
+                    return Ok(());
+                }
+            }
+
+            stream.write_all(&response)?;
+        }
+    }
+}
+
+fn start_data_thread(socket: UdpSocket, session: Arc<Mutex<Session>>, stopping: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut udp_buffer = [0; 1400];
+        while !stopping.load(Ordering::Relaxed) {
+            let count = match socket.recv_from(&mut udp_buffer) {
+                Ok((count, _)) => count,
+                Err(e) => {
+                    println!("[Emulator] Unable to receive UDP data: {}", e);
+                    thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+
+            if stopping.load(Ordering::Relaxed) {
+                break;
+            }
+
+            if let Err(e) = handle_data(&udp_buffer[..count], &session) {
+                println!("[Emulator] Bad data packet ({}): {:02x?}", e, &udp_buffer[..count]);
+            }
+        }
+    })
+}
+
+fn handle_data(packet: &[u8], session: &Mutex<Session>) -> Result<(), DecodeError> {
+    let mut session = session.lock().unwrap();
+    let session = &mut *session;
+    session.packets += 1;
+    let (packet, _) = DataPacket::decode(packet)?;
+
+    for entry in packet.entries {
+        session.last_data.insert(entry.handle, entry.data.clone());
+        match session.devices.get_mut(&entry.handle) {
+            Some(device) if device.last_data != entry.data => {
+                println!("[Emulator] Data handle={} device_slot={} pad_slot={}: {:02x?}", entry.handle, entry.device_slot, entry.pad_slot, entry.data);
+                device.last_data = entry.data;
+            },
+            Some(_) => {},
+            None => println!("[Emulator] Data for unknown handle {}", entry.handle)
+        }
+    }
+
+    Ok(())
+}
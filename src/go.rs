@@ -1,50 +1,32 @@
-use std::{sync::Arc, thread, time::Duration};
+use std::{sync::{Arc, atomic::Ordering}, thread, time::Instant};
 use std::num::NonZeroU32;
-use flume::{Receiver, Sender};
-use gilrs::{GamepadId, Gilrs, ff::{BaseEffect, BaseEffectType, Effect, EffectBuilder}};
-use crate::{commands::WriteCommand, controller_manager::ControllerManager, models::{ApplicationState, AttachData, DetachData, Controller, Rumble, TcpMessage, UdpMessage}};
+use flume::Receiver;
+use gilrs::{Gamepad, GamepadId, Gilrs, ff::{BaseEffect, BaseEffectType, Effect, EffectBuilder}};
+use crate::{registry::DeviceRegistry, attach::{self, AttachEvent, AttachQueue, AttachStatus, ControllerStatus}, commands::{ControllerReport, WriteCommand}, controller_manager::ControllerManager, network::{FrameSender, NetworkHandle}, protocol::{ConfigStatus, UserDataStatus}, models::{ApplicationState, ControllerConfig, InputState, DeviceIds, Controller, GENERIC_PID, GENERIC_VID, Rumble}, schedule::SendSchedule, state::SharedState};
 use governor::{Quota, RateLimiter, clock::{self, Clock}};
-use atomic::{Atomic, Ordering};
 
-fn gamepad_id_to_handle(gamepad_id: GamepadId) -> i32 {
-    let raw_id: usize = gamepad_id.into();
-    let max = (i32::MAX - 1) as usize;
-    ((raw_id % max) as i32) + 1
+fn uuid_to_string(uuid: [u8; 16]) -> String {
+    uuid.iter().map(|val| format!("{:02x}", val)).collect()
 }
 
-fn attach(gamepad_id: GamepadId, tcp_sender: &Sender<TcpMessage>) -> Option<Controller> {
-    let handle = gamepad_id_to_handle(gamepad_id);
-    let (s, r) = flume::bounded(0);
-    match tcp_sender.send(TcpMessage::Attach(AttachData { handle, response: s })) {
-        Ok(_) => {
-            match r.recv_timeout(Duration::from_secs(10)) {
-                Ok(val) => {
-                    match val {
-                        Some(val) => {
-                            if val.device_slot < 0 || val.pad_slot < 0 {
-                                println!("Unable to attach controller, invalid slots.")
-                            } else {
-                                return Some(Controller { id: gamepad_id, handle: handle, device_slot: val.device_slot, pad_slot: val.pad_slot, effect: None });
-                            }
-                        },
-                        None => println!("Unable to attach controller, no response received.")
-                    }
-                }
-                Err(e) => println!("Unable to attach controller, error on receive: {}", e)
-            };
-        },
-        Err(e) => println!("Unable to attach controller, error on send: {}", e)
-    };
+/// VID/PID announced for `gamepad`: an override, the real IDs or the generic ones.
+fn device_ids(gamepad: &Gamepad, options: &DeviceIds) -> (i16, i16) {
+    let uuid = gamepad.uuid();
+    let uuid_text = uuid_to_string(uuid);
+    if let Some(device) = options.overrides.iter().find(|device| device.device == gamepad.name() || device.device.eq_ignore_ascii_case(&uuid_text)) {
+        return (device.vid as i16, device.pid as i16);
+    }
 
-    None
-}
+    if options.use_real {
+        // gilrs follows the SDL layout: little endian vendor at 4 and product at 8, zero when unknown (e.g. XInput)
+        let vid = u16::from_le_bytes([uuid[4], uuid[5]]);
+        let pid = u16::from_le_bytes([uuid[8], uuid[9]]);
+        if vid != 0 {
+            return (vid as i16, pid as i16);
+        }
+    }
 
-fn dettach_gamepad(gamepad_id: GamepadId, tcp_sender: &Sender<TcpMessage>) {
-    let handle = gamepad_id_to_handle(gamepad_id);
-    match tcp_sender.send(TcpMessage::Detach(DetachData { handle })) {
-        Ok(_) => {},
-        Err(e) => println!("Unable to dettach controller: {}", e)
-    };
+    (GENERIC_VID, GENERIC_PID)
 }
 
 fn create_effect(id: GamepadId, gilrs: &mut Gilrs) -> Option<Effect> {
@@ -69,34 +51,128 @@ fn create_effect(id: GamepadId, gilrs: &mut Gilrs) -> Option<Effect> {
     }
 }
 
-fn attach_gamepad(gamepad_id: GamepadId, tcp_sender: &Sender<TcpMessage>, gilrs: &mut Gilrs) -> Option<Controller> {
-    let gamepad = gilrs.gamepad(gamepad_id);
-    match attach(gamepad_id, &tcp_sender) {
-        Some(controller) => {
-            println!("{} is {:?}. Attached!", gamepad.name(), gamepad.power_info());
-            Some(controller)
+/// Looks up the registered handle of each gamepad, a gamepad plugged in next to an identical one gets another.
+fn assign_handles(gamepad_ids: Vec<GamepadId>, registry: &mut DeviceRegistry, controllers: &[Controller], attach_queue: &AttachQueue, gilrs: &Gilrs) -> Vec<(GamepadId, i32)> {
+    let mut taken: Vec<i32> = controllers.iter().map(|controller| controller.handle).chain(attach_queue.handles()).collect();
+    gamepad_ids.into_iter()
+        .map(|gamepad_id| {
+            let gamepad = gilrs.gamepad(gamepad_id);
+            let handle = registry.handle(&uuid_to_string(gamepad.uuid()), gamepad.name(), &taken);
+            taken.push(handle);
+            (gamepad_id, handle)
+        })
+        .collect()
+}
+
+/// Queues the gamepads for attaching in the order they were first seen, so the console gives them the slots they had before.
+/// The results come back from the queue later.
+fn request_attach(mut gamepads: Vec<(GamepadId, i32)>, device_ids: &DeviceIds, network: &NetworkHandle, gilrs: &mut Gilrs, attach_queue: &mut AttachQueue, controllers: &mut Vec<Controller>, registry: &DeviceRegistry) {
+    gamepads.sort_by_key(|&(_, handle)| registry.position(handle));
+    for (gamepad_id, handle) in gamepads {
+        let gamepad = gilrs.gamepad(gamepad_id);
+        let ids = self::device_ids(&gamepad, device_ids);
+        if let Some(event) = attach_queue.request(gamepad_id, handle, gamepad.name(), ids, network) {
+            handle_attach_event(event, controllers, gilrs);
+        }
+    }
+}
+
+/// Another attached controller with the same slots means the console already knew the handle, likely from another client.
+/// The gamepad gets a new handle and is attached again.
+fn handle_collision(event: &AttachEvent, controllers: &[Controller], registry: &mut DeviceRegistry, gilrs: &Gilrs) -> Option<(GamepadId, i32)> {
+    let (id, handle) = match event {
+        AttachEvent::Attached { id, handle, .. } => (*id, *handle),
+        _ => return None
+    };
+    let other = attach::collision(event, controllers.iter().map(|controller| (controller.id, controller.device_slot, controller.pad_slot)))?;
+
+    println!("[Registry] Handle collision: the console gave {} the slots of {}, handle {} may be used by another client. Attaching it with a new one",
+        gilrs.gamepad(id).name(), gilrs.gamepad(other).name(), handle);
+    Some((id, registry.replace(handle)))
+}
+
+/// Adds a newly attached controller.
+fn handle_attach_event(event: AttachEvent, controllers: &mut Vec<Controller>, gilrs: &mut Gilrs) {
+    let (id, handle, response) = match event {
+        AttachEvent::Attached { id, handle, response } => (id, handle, response),
+        AttachEvent::Retrying { id } => {
+            println!("Retrying to attach {}", gilrs.gamepad(id).name());
+            return;
         },
-        None => {
-            println!("{} is {:?}. Unable to attach...", gamepad.name(), gamepad.power_info());
-            None
+        AttachEvent::Failed { id, reason, retry_in } => {
+            let gamepad = gilrs.gamepad(id);
+            println!("{} ({}) is {:?}. Unable to attach: {}. Retrying in {:.1} s", gamepad.name(), uuid_to_string(gamepad.uuid()), gamepad.power_info(), reason, retry_in.as_secs_f64());
+            return;
         }
+    };
+
+    match response.config {
+        ConfigStatus::Found => println!("Config found for this device."),
+        ConfigStatus::NotFound => println!("No config found for this device.")
+    }
+    if let UserDataStatus::Bad = response.user_data {
+        println!("Bad user data.");
+    }
+
+    let gamepad = gilrs.gamepad(id);
+    println!("{} ({}) is {:?}. Attached!", gamepad.name(), uuid_to_string(gamepad.uuid()), gamepad.power_info());
+    let effect = create_effect(id, gilrs);
+    controllers.push(Controller { id, handle, device_slot: response.device_slot, pad_slot: response.pad_slot, effect, report: None });
+}
+
+/// Sends `controllers` with nothing pressed and both sticks centered, while input is paused.
+/// Controllers being detached are released with their detach instead, a frame may be replaced before it is sent.
+fn release(controllers: &[Controller], change: u64, frame_sender: &mut FrameSender) {
+    let reports = controllers.iter().map(|controller| ControllerReport::new(controller, ControllerManager::neutral()));
+    let max_datagram = frame_sender.max_datagram();
+    if let Err(e) = frame_sender.send(|frame| {
+        frame.change = change;
+        frame.copy = 0;
+        WriteCommand::encode(reports, max_datagram, frame)
+    }) {
+        println!("[Controller] Unable to encode released controller data. Error: {}", e);
+    }
+}
+
+/// Lists the attached controllers and those still in the queue for `status`.
+fn publish_status(controllers: &[Controller], attach_queue: &AttachQueue, gilrs: &Gilrs, input: &InputState) {
+    let attached = controllers.iter().map(|controller| ControllerStatus {
+        name: gilrs.gamepad(controller.id).name().to_string(),
+        handle: controller.handle,
+        status: AttachStatus::Attached { device_slot: controller.device_slot, pad_slot: controller.pad_slot }
+    });
+    *input.controllers.lock().unwrap() = attached.chain(attach_queue.statuses()).collect();
+}
+
+/// Releases every button on the console and detaches all controllers, before the network loop closes the connection.
+/// Controllers still waiting to be attached are detached too, the console may have attached them already.
+fn shut_down(controllers: &[Controller], attach_queue: &AttachQueue, network: &NetworkHandle) {
+    for controller in controllers {
+        attach::detach(controller.handle, Some((controller.device_slot, controller.pad_slot)), network);
+    }
+    let pending: Vec<i32> = attach_queue.pending_handles().collect();
+    for &handle in &pending {
+        attach::detach(handle, None, network);
+    }
+
+    if controllers.len() + pending.len() > 0 {
+        println!("Detached {} controllers", controllers.len() + pending.len());
     }
 }
 
 pub fn go(
-    polling_rate: u32,
-    tcp_sender: Sender<TcpMessage>,
-    udp_sender: Sender<UdpMessage>,
+    config: ControllerConfig,
+    network: NetworkHandle,
+    mut frame_sender: FrameSender,
     reconection_notifier: Receiver<()>,
     rumble_receiver: Receiver<Rumble>,
-    application_state: Arc<Atomic<ApplicationState>>
+    application_state: Arc<SharedState>,
+    input: Arc<InputState>
 ) {
 
-    while application_state.load(Ordering::Relaxed).is_disconnected() {
-        thread::sleep(Duration::from_secs(1));
-    }
-
-    if application_state.load(Ordering::Relaxed).is_exiting() {
+    if application_state.wait_while(|state| state.is_disconnected(), None).is_stopping() {
+        // nothing attached yet, the network loop can close right away
+        application_state.set(ApplicationState::Exiting);
         return;
     }
 
@@ -104,30 +180,33 @@ pub fn go(
 
     // Iterate over all connected gamepads and attach them
     let mut controllers = Vec::new();
+    let mut attach_queue = AttachQueue::default();
 
-    for gamepad_id in gilrs.gamepads().map(|(gamepad_id, _)| gamepad_id).collect::<Vec<GamepadId>>() {
-        if let Some(mut controller) = attach_gamepad(gamepad_id, &tcp_sender, &mut gilrs) {
-            controller.effect = create_effect(gamepad_id, &mut gilrs);
-            controllers.push(controller);
-        }
-    }
+    let mut registry = DeviceRegistry::load(config.registry.clone());
+
+    let gamepads = assign_handles(gilrs.gamepads().map(|(gamepad_id, _)| gamepad_id).collect(), &mut registry, &controllers, &attach_queue, &gilrs);
+    request_attach(gamepads, &config.device_ids, &network, &mut gilrs, &mut attach_queue, &mut controllers, &registry);
+    publish_status(&controllers, &attach_queue, &gilrs, &input);
 
     let controller_manager = ControllerManager::new();
     let clock = clock::DefaultClock::default();
     let limiter = RateLimiter::direct_with_clock(
-        Quota::per_second(NonZeroU32::new(polling_rate).unwrap()).allow_burst(NonZeroU32::new(1u32).unwrap()),
+        Quota::per_second(NonZeroU32::new(config.polling_rate).unwrap()).allow_burst(NonZeroU32::new(1u32).unwrap()),
         &clock
     );
+    let mut schedule = SendSchedule::new(config.send_mode, Instant::now());
+    let mut change = 0;
+    let mut released = false;
 
-    let send_timeout = Duration::from_secs(1);
     loop {
-        match application_state.load(Ordering::Relaxed) {
-            ApplicationState::Disconnected => {
-                thread::sleep(Duration::from_secs(1));
-                continue;
-            },
+        match application_state.wait_while(|state| state.is_disconnected(), None) {
             ApplicationState::Exiting => return,
-            ApplicationState::Connected => {}
+            ApplicationState::ShuttingDown => {
+                shut_down(&controllers, &attach_queue, &network);
+                application_state.set(ApplicationState::Exiting);
+                return;
+            },
+            _ => {}
         }
 
         if let Err(e) = limiter.check() {
@@ -154,52 +233,98 @@ pub fn go(
             };
         }
 
-        if reconection_notifier.try_recv().is_ok() {
-            for controller in &mut controllers {
-                match attach(controller.id, &tcp_sender) {
-                    Some(new_data) => {
-                        controller.pad_slot = new_data.pad_slot;
-                        controller.device_slot = new_data.device_slot;
-                    },
-                    None => {}
+        let events = attach_queue.poll(&network, Instant::now());
+        if !events.is_empty() {
+            for event in events {
+                if let Some(gamepad) = handle_collision(&event, &controllers, &mut registry, &gilrs) {
+                    if let AttachEvent::Attached { handle, .. } = event {
+                        attach::detach(handle, None, &network);
+                    }
+                    request_attach(vec![gamepad], &config.device_ids, &network, &mut gilrs, &mut attach_queue, &mut controllers, &registry);
+                    continue;
                 }
+                handle_attach_event(event, &mut controllers, &mut gilrs);
             }
+            publish_status(&controllers, &attach_queue, &gilrs, &input);
+        }
+
+        if reconection_notifier.try_recv().is_ok() {
+            // the slots of the old connection mean nothing now, input waits for the new ones
+            let gamepads = std::mem::take(&mut controllers).iter().map(|controller| (controller.id, controller.handle)).collect();
+            request_attach(gamepads, &config.device_ids, &network, &mut gilrs, &mut attach_queue, &mut controllers, &registry);
+            attach_queue.retry_now();
+            publish_status(&controllers, &attach_queue, &gilrs, &input);
         }
 
         while let Some(event) = gilrs.next_event() {
             match event.event {
-                gilrs::EventType::Connected => {
-                    if !controllers.iter().any(|controller| controller.id == event.id) {
-                        println!("Attaching {}", gilrs.gamepad(event.id).name());
-                        if let Some(mut controller) = attach_gamepad(event.id, &tcp_sender, &mut gilrs) {
-                            controller.effect = create_effect(event.id, &mut gilrs);
-                            controllers.push(controller);
-                        }
-                    }
+                gilrs::EventType::Connected if !controllers.iter().any(|controller| controller.id == event.id) && !attach_queue.contains(event.id) => {
+                    println!("Attaching {}", gilrs.gamepad(event.id).name());
+                    let gamepads = assign_handles(vec![event.id], &mut registry, &controllers, &attach_queue, &gilrs);
+                    request_attach(gamepads, &config.device_ids, &network, &mut gilrs, &mut attach_queue, &mut controllers, &registry);
+                    publish_status(&controllers, &attach_queue, &gilrs, &input);
                 },
-                gilrs::EventType::Disconnected => {
-                    if controllers.iter().any(|controller| controller.id == event.id) {
-                        println!("Dettaching {}", gilrs.gamepad(event.id).name());
-                        dettach_gamepad(event.id, &tcp_sender);
-                        controllers.retain(|c| c.id != event.id);
+                gilrs::EventType::Disconnected if controllers.iter().any(|controller| controller.id == event.id) || attach_queue.contains(event.id) => {
+                    println!("Dettaching {}", gilrs.gamepad(event.id).name());
+                    let queued = attach_queue.cancel(event.id);
+                    match controllers.iter().find(|controller| controller.id == event.id) {
+                        Some(controller) => attach::detach(controller.handle, Some((controller.device_slot, controller.pad_slot)), &network),
+                        None => if let Some(handle) = queued {
+                            attach::detach(handle, None, &network);
+                        }
                     }
+                    controllers.retain(|c| c.id != event.id);
+                    publish_status(&controllers, &attach_queue, &gilrs, &input);
                 },
                 _ => {}
             }
         }
-        
-        let mut commands = Vec::new();
-        for controller in &controllers {
-            let gamepad = gilrs.gamepad(controller.id);
-            commands.push((controller, controller_manager.poll(&gamepad)));
+
+        if controllers.is_empty() {
+            continue;
+        }
+
+        if input.paused.load(Ordering::Relaxed) {
+            if !released {
+                // what the console sees changes, as with any other input
+                change += 1;
+                release(&controllers, change, &mut frame_sender);
+                released = true;
+                println!("Input paused, controllers released");
+            }
+            continue;
+        }
+
+        if released {
+            // everything is sent again once resumed
+            released = false;
+            for controller in &mut controllers {
+                controller.report = None;
+            }
+            println!("Input resumed");
+        }
+
+        let mut changed = false;
+        for controller in &mut controllers {
+            let report = controller_manager.poll(&gilrs.gamepad(controller.id));
+            changed |= controller.report != Some(report);
+            controller.report = Some(report);
+        }
+
+        if changed {
+            change += 1;
         }
 
-        if commands.len() > 0 {
-            let write_command =
-                WriteCommand::new(&commands, 1);
-            match udp_sender.send_timeout(UdpMessage::UdpData(Box::new(write_command)), send_timeout) {
-                Err(e) => println!("Unable to send data to thread: {}", e),
-                Ok(_) => {}
+        if let Some(copy) = schedule.poll(changed, Instant::now()) {
+            let reports = controllers.iter()
+                .filter_map(|controller| controller.report.map(|report| ControllerReport::new(controller, report)));
+            let max_datagram = frame_sender.max_datagram();
+            if let Err(e) = frame_sender.send(|frame| {
+                frame.change = change;
+                frame.copy = copy;
+                WriteCommand::encode(reports, max_datagram, frame)
+            }) {
+                println!("[Controller] Unable to encode controller data. Dropping frame. Error: {}", e);
             }
         }
     }
@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+use crate::models::Direction;
+
+/// Extra time a reordered datagram is held back, so the ones after it overtake it.
+const REORDER_HOLD: Duration = Duration::from_millis(20);
+
+/// Network trouble to simulate, for reproducing flaky connections against the emulator.
+#[derive(Clone, Default)]
+pub struct ImpairmentConfig {
+    /// Share of UDP datagrams lost, from 0 to 1.
+    pub drop: f64,
+    pub delay: Duration,
+    /// The delay of each datagram randomly varies by up to this much either way.
+    pub jitter: Duration,
+    /// Share of UDP datagrams delivered twice.
+    pub duplicate: f64,
+    /// Share of UDP datagrams delivered after the ones sent later.
+    pub reorder: f64,
+    /// Drops the control connection after it was up for this long.
+    pub disconnect: Option<Duration>
+}
+
+struct Delayed {
+    at: Instant,
+    direction: Direction,
+    data: Vec<u8>
+}
+
+/// Sits between the network loop and its UDP sockets, holding back, losing or repeating datagrams.
+pub struct Impairment {
+    config: ImpairmentConfig,
+    queue: Vec<Delayed>,
+    disconnect_at: Option<Instant>
+}
+
+impl Impairment {
+    pub fn new(config: ImpairmentConfig) -> Impairment {
+        Impairment {
+            config,
+            queue: Vec::new(),
+            disconnect_at: None
+        }
+    }
+
+    /// Passes a datagram through. It comes back out of `take` once due, if at all.
+    pub fn push(&mut self, direction: Direction, data: &[u8], now: Instant) {
+        if fastrand::f64() < self.config.drop {
+            return;
+        }
+
+        let copies = if fastrand::f64() < self.config.duplicate { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut at = now + self.delay();
+            if fastrand::f64() < self.config.reorder {
+                at += REORDER_HOLD;
+            }
+            self.queue.push(Delayed { at, direction, data: data.to_vec() });
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        let jitter = self.config.jitter.as_secs_f64() * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((self.config.delay.as_secs_f64() + jitter).max(0.0))
+    }
+
+    /// The earliest datagram going in `direction` that is due by `now`.
+    pub fn take(&mut self, direction: Direction, now: Instant) -> Option<Vec<u8>> {
+        let index = self.queue.iter().enumerate()
+            .filter(|(_, delayed)| delayed.direction == direction && delayed.at <= now)
+            .min_by_key(|(_, delayed)| delayed.at)
+            .map(|(index, _)| index)?;
+
+        Some(self.queue.swap_remove(index).data)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.iter().map(|delayed| delayed.at).chain(self.disconnect_at).min()
+    }
+
+    /// Whether to drop the control connection now, `connected` tells whether there is one.
+    pub fn disconnect_due(&mut self, connected: bool, now: Instant) -> bool {
+        let interval = match self.config.disconnect {
+            Some(val) if connected => val,
+            _ => {
+                self.disconnect_at = None;
+                return false;
+            }
+        };
+
+        match self.disconnect_at {
+            Some(at) if at <= now => {
+                self.disconnect_at = None;
+                true
+            },
+            Some(_) => false,
+            None => {
+                self.disconnect_at = Some(now + interval);
+                false
+            }
+        }
+    }
+}
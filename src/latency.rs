@@ -0,0 +1,147 @@
+use std::{collections::VecDeque, fmt, time::Duration};
+
+/// Round trips kept for the statistics, about five minutes of pings.
+const WINDOW: usize = 300;
+
+/// Rolling statistics over the most recent ping round trips.
+#[derive(Default)]
+pub struct LatencyStats {
+    samples: VecDeque<Duration>,
+    missed: u32,
+    total_missed: u64
+}
+
+pub struct LatencySummary {
+    pub min: Duration,
+    pub mean: Duration,
+    pub p99: Duration,
+    /// Mean difference between consecutive round trips.
+    pub jitter: Duration,
+    pub samples: usize
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+        self.missed = 0;
+    }
+
+    pub fn record_missed(&mut self) {
+        self.missed += 1;
+        self.total_missed += 1;
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let count = self.samples.len();
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+
+        let total: Duration = sorted.iter().sum();
+        let p99_index = (count * 99).div_ceil(100) - 1;
+        let differences: Duration = self.samples.iter().zip(self.samples.iter().skip(1))
+            .map(|(previous, next)| if next > previous { *next - *previous } else { *previous - *next })
+            .sum();
+
+        Some(LatencySummary {
+            min: sorted[0],
+            mean: total / count as u32,
+            p99: sorted[p99_index],
+            jitter: if count > 1 { differences / (count - 1) as u32 } else { Duration::ZERO },
+            samples: count
+        })
+    }
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.summary() {
+            Some(summary) => write!(f, "RTT min {:.2} ms, mean {:.2} ms, p99 {:.2} ms, jitter {:.2} ms over {} pings",
+                millis(summary.min), millis(summary.mean), millis(summary.p99), millis(summary.jitter), summary.samples)?,
+            None => write!(f, "no RTT measured yet")?
+        }
+        write!(f, ", {} pongs missed ({} in a row)", self.total_missed, self.missed)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LatencyStats, WINDOW};
+
+    fn ms(val: u64) -> Duration {
+        Duration::from_millis(val)
+    }
+
+    fn stats(samples: impl IntoIterator<Item = u64>) -> LatencyStats {
+        let mut stats = LatencyStats::default();
+        for sample in samples {
+            stats.record(ms(sample));
+        }
+        stats
+    }
+
+    #[test]
+    fn no_summary_without_samples() {
+        assert!(LatencyStats::default().summary().is_none());
+    }
+
+    #[test]
+    fn single_sample_is_everything_but_jitter() {
+        let summary = stats([7]).summary().unwrap();
+        assert_eq!((summary.min, summary.mean, summary.p99, summary.jitter, summary.samples), (ms(7), ms(7), ms(7), Duration::ZERO, 1));
+    }
+
+    #[test]
+    fn min_mean_and_jitter() {
+        let summary = stats([10, 30, 20]).summary().unwrap();
+        assert_eq!(summary.min, ms(10));
+        assert_eq!(summary.mean, ms(20));
+        // |30 - 10| and |20 - 30|, in the order they were recorded
+        assert_eq!(summary.jitter, ms(15));
+    }
+
+    #[test]
+    fn p99_rounds_its_index_up() {
+        // 99% of 10 samples is 9.9, so the 10th
+        assert_eq!(stats((1..=10).rev()).summary().unwrap().p99, ms(10));
+        assert_eq!(stats(1..=100).summary().unwrap().p99, ms(99));
+        // 99% of 101 samples is 99.99, so the 100th
+        assert_eq!(stats(1..=101).summary().unwrap().p99, ms(100));
+    }
+
+    #[test]
+    fn oldest_samples_roll_out_of_the_window() {
+        let mut stats = stats(1..=WINDOW as u64);
+        assert_eq!(stats.summary().unwrap().min, ms(1));
+
+        stats.record(ms(1000));
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.samples, WINDOW);
+        assert_eq!(summary.min, ms(2));
+        // 2 to 300 ms and the new one
+        assert_eq!(summary.mean, ms((2..=300).sum::<u64>() + 1000) / WINDOW as u32);
+    }
+
+    #[test]
+    fn missed_pongs_in_a_row_reset_on_a_pong() {
+        let mut stats = stats([]);
+        stats.record_missed();
+        stats.record_missed();
+        assert!(stats.to_string().ends_with("2 pongs missed (2 in a row)"));
+
+        stats.record(ms(5));
+        assert!(stats.to_string().ends_with("2 pongs missed (0 in a row)"));
+    }
+}
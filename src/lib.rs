@@ -0,0 +1,21 @@
+//! HIDtoVPAD network client, with the Wii U emulator it is tested against.
+
+pub mod go;
+pub mod attach;
+pub mod network;
+pub mod capture;
+pub mod commands;
+pub mod controller_manager;
+pub mod models;
+pub mod protocol;
+pub mod emulator;
+pub mod discovery;
+pub mod latency;
+pub mod reconnect;
+pub mod registry;
+pub mod safety;
+pub mod schedule;
+pub mod state;
+pub mod trace;
+pub mod triple_buffer;
+pub mod impairment;
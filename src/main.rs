@@ -1,21 +1,26 @@
-use std::{sync::{Arc, atomic::Ordering}};
-use clap::{Arg, App};
+use std::{io::BufRead, sync::{Arc, Mutex, atomic::Ordering}, thread::JoinHandle, time::{Duration, Instant}};
+use clap::{Arg, ArgMatches, App, AppSettings, SubCommand};
 
-use atomic::Atomic;
-use models::ApplicationState;
+use network_client::{discovery, emulator, go, impairment, latency, models, network, reconnect, registry, state};
+use discovery::Subnet;
+use impairment::ImpairmentConfig;
+use latency::LatencyStats;
+use network::NetworkHandle;
+use models::{ApplicationState, BaseProtocol, ControllerConfig, DeviceIdOverride, DeviceIds, InputState, NetworkConfig, ProtocolVersion, SendMode};
+use reconnect::ReconnectPolicy;
+use state::SharedState;
 
-use std::net::IpAddr;
+use std::{net::IpAddr, path::PathBuf};
 
-mod go;
-mod network;
-mod commands;
-mod controller_manager;
-mod models;
+/// How long detaching the controllers and stopping each thread may take when exiting,
+/// on top of the control timeout the network loop may spend handing the last messages to the console.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
 
 fn main() {
     let matches =
         App::new("Command line HIDtoVPAD network client")
             .version("v1.0.0")
+            .setting(AppSettings::SubcommandsNegateReqs)
             .arg(Arg::with_name("polling-rate")
                 .short("p")
                 .long("polling-rate")
@@ -40,72 +45,534 @@ fn main() {
                     }
                 })
                 .takes_value(true))
-            .arg(Arg::with_name("ip")
-                .help("Sets the IP address to connect, for example 192.168.2.3")
+            .arg(Arg::with_name("send-mode")
+                .long("send-mode")
+                .help("Sends controller data on every poll, or only when it changed")
+                .possible_values(&["fixed", "on-change"])
+                .default_value("fixed")
+                .takes_value(true))
+            .arg(Arg::with_name("keepalive")
+                .long("keepalive")
+                .value_name("ms")
+                .help("With --send-mode on-change, resends unchanged controller data this often in case a packet was lost")
+                .default_value("100")
+                .validator(|val| parse_count(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("redundancy")
+                .long("redundancy")
+                .value_name("copies")
+                .help("With --send-mode on-change, sends every change this many more times, for lossy Wi-Fi")
+                .default_value("0")
+                .validator(|val| val.parse::<u32>().map(|_| ()).map_err(|e| format!("Unable to parse redundancy: {}", e)))
+                .takes_value(true))
+            .arg(Arg::with_name("redundancy-spacing")
+                .long("redundancy-spacing")
+                .value_name("ms")
+                .help("Time between the copies sent by --redundancy, rounded up to the next poll")
+                .default_value("10")
+                .validator(|val| val.parse::<u64>().map(|_| ()).map_err(|e| format!("Unable to parse redundancy-spacing: {}", e)))
+                .takes_value(true))
+            .arg(Arg::with_name("real-device-ids")
+                .long("real-device-ids")
+                .help("Sends each gamepad's real vendor and product ID so configs stored on the Wii U apply. Falls back to the generic ID when the Wii U has no config for it."))
+            .arg(Arg::with_name("device-id")
+                .long("device-id")
+                .value_name("DEVICE=VID:PID")
+                .help("Sends fixed hexadecimal IDs for the gamepad with this name or UUID, for example \"Xbox Series Controller=045e:0b12\"")
+                .validator(|val| parse_device_id(&val).map(|_| ()))
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true))
+            .arg(Arg::with_name("registry")
+                .long("registry")
+                .value_name("file")
+                .help("Keeps the handle of every gamepad seen in this file, so it stays the same across restarts and replugs and gamepads are attached in the same order [default: devices.txt in the user's config directory]")
+                .takes_value(true))
+            .arg(Arg::with_name("no-registry")
+                .long("no-registry")
+                .conflicts_with("registry")
+                .help("Does not keep the gamepads seen, their handles only last until the client exits"))
+            .arg(Arg::with_name("discover")
+                .short("d")
+                .long("discover")
+                .value_name("network")
+                .help("Scans for a Wii U running HIDtoVPAD and connects to the first one found. Scans the local /24 unless a network such as 192.168.2.0/24 is given.")
+                .validator(|val| val.parse::<Subnet>().map(|_| ()))
+                .conflicts_with("host")
+                .min_values(0)
+                .max_values(1)
+                .takes_value(true))
+            .arg(Arg::with_name("list")
+                .long("list")
+                .help("Lists every server found by --discover and exits")
+                .requires("discover"))
+            .arg(port_arg("tcp-port", "Port of the control connection on the Wii U [default: 8112]"))
+            .arg(port_arg("udp-port", "Port the Wii U receives controller data on [default: 8113]"))
+            .arg(port_arg("data-port", "Local port controller data is sent from [default: 8113]"))
+            .arg(port_arg("rumble-port", "Local port the Wii U sends rumble to [default: 8114]"))
+            .arg(Arg::with_name("bind")
+                .long("bind")
+                .value_name("address")
+                .help("Local address used for all connections to the Wii U")
+                .validator(|val| val.parse::<IpAddr>().map(|_| ()).map_err(|e| format!("Unable to parse bind address: {}", e)))
+                .takes_value(true))
+            .arg(Arg::with_name("interface")
+                .long("interface")
+                .value_name("name")
+                .help("Network interface used for all connections to the Wii U, for example wlan0. Linux only.")
+                .takes_value(true))
+            .arg(Arg::with_name("reconnect-delay")
+                .long("reconnect-delay")
+                .value_name("ms")
+                .help("Wait before the first reconnection attempt, doubled after every failed one")
+                .default_value("500")
+                .validator(|val| val.parse::<u64>().map(|_| ()).map_err(|e| format!("Unable to parse reconnect-delay: {}", e)))
+                .takes_value(true))
+            .arg(Arg::with_name("reconnect-max-delay")
+                .long("reconnect-max-delay")
+                .value_name("ms")
+                .help("Longest wait between reconnection attempts")
+                .default_value("30000")
+                .validator(|val| val.parse::<u64>().map(|_| ()).map_err(|e| format!("Unable to parse reconnect-max-delay: {}", e)))
+                .takes_value(true))
+            .arg(Arg::with_name("reconnect-jitter")
+                .long("reconnect-jitter")
+                .value_name("percent")
+                .help("Randomly shortens or lengthens each wait by up to this much")
+                .default_value("20")
                 .validator(|val| {
-                    match val.parse::<IpAddr>() {
-                        Err(e) => Err(format!("Unable to parse IP address. Error: {}", e)),
-                        Ok(_) => Ok(())
+                    match val.parse::<u32>() {
+                        Ok(val) if val > 100 => Err("Reconnect jitter must be at most 100%".to_owned()),
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(format!("Unable to parse reconnect-jitter: {}", e))
                     }
                 })
-                .required(true))
+                .takes_value(true))
+            .arg(Arg::with_name("reconnect-attempts")
+                .long("reconnect-attempts")
+                .value_name("count")
+                .help("Exits with an error after this many failed connection attempts in a row. Retries forever by default.")
+                .validator(|val| parse_count(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("exit-after-failures")
+                .long("exit-after-failures")
+                .value_name("count")
+                .help("Exits with an error once the connection to the Wii U was lost this many times")
+                .validator(|val| parse_count(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("control-timeout")
+                .long("control-timeout")
+                .value_name("ms")
+                .help("Time allowed for connecting and for each exchange on the control connection before reconnecting")
+                .default_value("2000")
+                .validator(|val| parse_count(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("ping-timeout")
+                .long("ping-timeout")
+                .value_name("ms")
+                .help("Time the Wii U has to answer a ping before it counts as missed")
+                .default_value("1000")
+                .validator(|val| val.parse::<u64>().map(|_| ()).map_err(|e| format!("Unable to parse ping-timeout: {}", e)))
+                .takes_value(true))
+            .arg(Arg::with_name("max-missed-pongs")
+                .long("max-missed-pongs")
+                .value_name("count")
+                .help("Reconnects after this many pings in a row went unanswered")
+                .default_value("3")
+                .validator(|val| parse_count(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("mtu")
+                .long("mtu")
+                .value_name("bytes")
+                .help("Largest packet controller data is sent in, more controllers are split over several packets")
+                .default_value("1500")
+                .validator(|val| parse_mtu(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("impair")
+                .long("impair")
+                .value_name("spec")
+                .help("Simulates a bad network for testing, for example \"drop=5,delay=30,jitter=10,duplicate=1,reorder=2,disconnect=60\". \
+                    Percentages of UDP packets dropped, duplicated and reordered, delay and jitter in ms, \
+                    seconds until the control connection is dropped.")
+                .validator(|val| parse_impairment(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("trace")
+                .long("trace")
+                .help("Logs every message exchanged with the Wii U, with its meaning and a hex dump"))
+            .arg(Arg::with_name("capture")
+                .long("capture")
+                .value_name("file")
+                .help("Writes every message exchanged with the Wii U to this pcap file, for Wireshark")
+                .takes_value(true))
+            .arg(Arg::with_name("status-interval")
+                .long("status-interval")
+                .value_name("seconds")
+                .help("Prints the connection status and round trip times this often. Typing \"status\" prints them at any time.")
+                .validator(|val| parse_count(&val).map(|_| ()))
+                .takes_value(true))
+            .arg(Arg::with_name("host")
+                .help("Sets the host name or IP address to connect, for example 192.168.2.3, wiiu.local or fd00::3")
+                .validator(|val| {
+                    if val.is_empty() || val.contains(char::is_whitespace) {
+                        Err(format!("Not a valid host name or IP address: \"{}\"", val))
+                    } else {
+                        Ok(())
+                    }
+                })
+                .required_unless("discover"))
+            .subcommand(SubCommand::with_name("emulator")
+                .about("Emulates the Wii U side of HIDtoVPAD, for testing without a console")
+                .arg(Arg::with_name("protocol-version")
+                    .long("protocol-version")
+                    .help("Protocol version offered to the client")
+                    .possible_values(&["1", "2", "3"])
+                    .default_value("3")
+                    .takes_value(true))
+                .arg(Arg::with_name("config-found")
+                    .long("config-found")
+                    .help("Answers attach requests with \"config found\" instead of \"config not found\""))
+                .arg(Arg::with_name("bad-user-data")
+                    .long("bad-user-data")
+                    .help("Answers attach requests with \"bad user data\""))
+                .arg(Arg::with_name("device-slot")
+                    .long("device-slot")
+                    .help("Device slot given to the first attached controller, the next ones count up from it")
+                    .default_value("0")
+                    .validator(|val| val.parse::<i16>().map(|_| ()).map_err(|e| format!("Unable to parse device-slot: {}", e)))
+                    .takes_value(true))
+                .arg(Arg::with_name("pad-slot")
+                    .long("pad-slot")
+                    .help("Pad slot given to attached controllers")
+                    .default_value("0")
+                    .validator(|val| val.parse::<i8>().map(|_| ()).map_err(|e| format!("Unable to parse pad-slot: {}", e)))
+                    .takes_value(true))
+                .arg(Arg::with_name("unresponsive")
+                    .long("unresponsive")
+                    .help("Stops answering the client, either from the start or right after the handshake")
+                    .possible_values(&["handshake", "requests"])
+                    .takes_value(true))
+                .arg(port_arg("tcp-port", "Port to listen on for the control connection [default: 8112]"))
+                .arg(port_arg("udp-port", "Port to listen on for controller data [default: 8113]"))
+                .arg(port_arg("rumble-port", "Port of the client rumble is sent to [default: 8114]")))
             .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("emulator") {
+        run_emulator(emulator::EmulatorConfig {
+            version: match matches.value_of("protocol-version").unwrap() {
+                "1" => ProtocolVersion::Version1,
+                "2" => ProtocolVersion::Version2,
+                _ => ProtocolVersion::Version3
+            },
+            config_found: matches.is_present("config-found"),
+            user_data_okay: !matches.is_present("bad-user-data"),
+            device_slot: matches.value_of("device-slot").unwrap().parse::<i16>().unwrap(),
+            pad_slot: matches.value_of("pad-slot").unwrap().parse::<i8>().unwrap(),
+            tcp_port: port_value(matches, "tcp-port", BaseProtocol::TcpPort),
+            udp_port: port_value(matches, "udp-port", BaseProtocol::UdpPort),
+            rumble_port: port_value(matches, "rumble-port", BaseProtocol::UdpServerPort),
+            unresponsive: matches.value_of("unresponsive").map(|val| match val {
+                "handshake" => emulator::Unresponsive::Handshake,
+                _ => emulator::Unresponsive::Requests
+            })
+        });
+        return;
+    }
+
     let _timer = Timer::new(1);
 
-    let addr: IpAddr = matches.value_of("ip").unwrap().parse::<IpAddr>().unwrap();
-    let polling_rate: u32 = matches.value_of("polling-rate").unwrap().parse::<u32>().unwrap();
+    let tcp_port = port_value(&matches, "tcp-port", BaseProtocol::TcpPort);
+    let host: String = match matches.value_of("host") {
+        Some(val) => val.trim_start_matches('[').trim_end_matches(']').to_owned(),
+        None => {
+            let subnet = match matches.value_of("discover") {
+                Some(val) => val.parse::<Subnet>().unwrap(),
+                None => match Subnet::local() {
+                    Some(val) => val,
+                    None => {
+                        println!("Unable to find the local network, please give one to --discover");
+                        std::process::exit(1);
+                    }
+                }
+            };
 
-    let (tcp_command_sender, tcp_command_receiver) = flume::unbounded();
-    let (udp_command_sender, udp_command_receiver) = flume::bounded(0);
+            let list = matches.is_present("list");
+            let candidates = discovery::scan(subnet, tcp_port, !list);
+            if list {
+                for candidate in &candidates {
+                    println!("{}\t{:?}", candidate.ip, candidate.version);
+                }
+                return;
+            }
+
+            match candidates.first() {
+                Some(candidate) => candidate.ip.to_string(),
+                None => {
+                    println!("No HIDtoVPAD server found on {}", subnet);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+    let network_config = NetworkConfig {
+        host,
+        tcp_port,
+        udp_port: port_value(&matches, "udp-port", BaseProtocol::UdpPort),
+        data_port: port_value(&matches, "data-port", BaseProtocol::UdpPort),
+        rumble_port: port_value(&matches, "rumble-port", BaseProtocol::UdpServerPort),
+        bind_address: matches.value_of("bind").map(|val| val.parse::<IpAddr>().unwrap()),
+        interface: matches.value_of("interface").map(|val| val.to_owned()),
+        reconnect: ReconnectPolicy {
+            initial_delay: Duration::from_millis(matches.value_of("reconnect-delay").unwrap().parse::<u64>().unwrap()),
+            max_delay: Duration::from_millis(matches.value_of("reconnect-max-delay").unwrap().parse::<u64>().unwrap()),
+            jitter: matches.value_of("reconnect-jitter").unwrap().parse::<u32>().unwrap() as f64 / 100.0,
+            max_attempts: matches.value_of("reconnect-attempts").map(|val| parse_count(val).unwrap()),
+            max_failures: matches.value_of("exit-after-failures").map(|val| parse_count(val).unwrap())
+        },
+        control_timeout: Duration::from_millis(parse_count(matches.value_of("control-timeout").unwrap()).unwrap() as u64),
+        ping_timeout: Duration::from_millis(matches.value_of("ping-timeout").unwrap().parse::<u64>().unwrap()),
+        max_missed_pongs: parse_count(matches.value_of("max-missed-pongs").unwrap()).unwrap(),
+        mtu: parse_mtu(matches.value_of("mtu").unwrap()).unwrap(),
+        impairment: matches.value_of("impair").map(|val| parse_impairment(val).unwrap()),
+        trace: matches.is_present("trace"),
+        capture: matches.value_of("capture").map(PathBuf::from)
+    };
+    let status_interval = matches.value_of("status-interval").map(|val| Duration::from_secs(parse_count(val).unwrap() as u64));
+    let controller_config = ControllerConfig {
+        polling_rate: matches.value_of("polling-rate").unwrap().parse::<u32>().unwrap(),
+        send_mode: match matches.value_of("send-mode").unwrap() {
+            "on-change" => SendMode::OnChange {
+                keepalive: Duration::from_millis(parse_count(matches.value_of("keepalive").unwrap()).unwrap() as u64),
+                copies: matches.value_of("redundancy").unwrap().parse::<u32>().unwrap(),
+                spacing: Duration::from_millis(matches.value_of("redundancy-spacing").unwrap().parse::<u64>().unwrap())
+            },
+            _ => SendMode::Fixed
+        },
+        device_ids: DeviceIds {
+            use_real: matches.is_present("real-device-ids"),
+            overrides: matches.values_of("device-id")
+                .map(|values| values.map(|val| parse_device_id(val).unwrap()).collect())
+                .unwrap_or_default()
+        },
+        registry: if matches.is_present("no-registry") { None } else { matches.value_of("registry").map(PathBuf::from).or_else(registry::default_path) }
+    };
 
     let (reconection_notifier_sender, reconection_notifier_receiver) = flume::unbounded(); // use BUS
 
-    let (rumble_sender, rumble_receiver) = flume::bounded(0);
+    // the network loop never waits on it, rumble commands beyond this are dropped
+    let (rumble_sender, rumble_receiver) = flume::bounded(32);
 
-    let application_state = Arc::new(Atomic::new(ApplicationState::Disconnected));
+    let application_state = Arc::new(SharedState::new(ApplicationState::Disconnected));
+    let latency = Arc::new(Mutex::new(LatencyStats::default()));
 
-    let network_thread = network::start_thread(
-        addr,
-        tcp_command_sender.clone(),
-        tcp_command_receiver,
-        udp_command_receiver.clone(),
+    let control_timeout = network_config.control_timeout;
+    let (network, frame_sender, network_thread) = match network::start(
+        network_config,
         reconection_notifier_sender,
         rumble_sender,
-        application_state.clone());
+        application_state.clone(),
+        latency.clone()) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Unable to start networking: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let input = Arc::new(InputState::default());
 
     let go_thread = std::thread::spawn({
         let application_state = application_state.clone();
+        let input = input.clone();
+        let network = network.clone();
         move || {
-            go::go(polling_rate,
-                tcp_command_sender,
-                udp_command_sender,
+            go::go(controller_config,
+                network,
+                frame_sender,
                 reconection_notifier_receiver,
                 rumble_receiver,
-                application_state
+                application_state,
+                input
             );
         }
     });
 
+    // SIGINT and SIGTERM, a second one skips detaching
     ctrlc::set_handler({
         let application_state = application_state.clone();
         move || {
-            application_state.store(ApplicationState::Exiting, Ordering::Relaxed);
-            println!("### Press enter to finish ###");
+            if application_state.get().is_shutting_down() {
+                application_state.set(ApplicationState::Exiting);
+            } else {
+                application_state.set(ApplicationState::ShuttingDown);
+            }
         }
     })
     .expect("Error setting Ctrl-C handler");
 
+    // never joined, it stays blocked on stdin when exiting for another reason
+    std::thread::spawn({
+        let application_state = application_state.clone();
+        let latency = latency.clone();
+        let network = network.clone();
+        let input = input.clone();
+        move || {
+            let mut line = String::new();
+            while std::io::stdin().read_line(&mut line).is_ok() {
+                match line.trim() {
+                    "status" => print_status(&application_state, &latency, &network, &input),
+                    "pause" => input.paused.store(true, Ordering::Relaxed),
+                    "resume" => input.paused.store(false, Ordering::Relaxed),
+                    _ => break
+                }
+                line.clear();
+            }
+            application_state.set(ApplicationState::ShuttingDown);
+        }
+    });
+
     println!("### Press enter to exit ###");
-    let _ = std::io::stdin().read_line(&mut String::new());
+    while !application_state.wait_while(|state| !state.is_stopping(), status_interval).is_stopping() {
+        print_status(&application_state, &latency, &network, &input);
+    }
     println!("---> Exiting <---");
 
-    application_state.store(ApplicationState::Exiting, Ordering::Relaxed);
-    let _ = network_thread.join();
-    let _ = go_thread.join();
+    // the controller thread moves on to exiting once everything is detached
+    if !application_state.wait_while(|state| !state.is_exiting(), Some(SHUTDOWN_TIMEOUT)).is_exiting() {
+        println!("Controllers were not detached in time, closing anyway");
+        application_state.set(ApplicationState::Exiting);
+    }
+
+    let result = join_timeout(network_thread, control_timeout + SHUTDOWN_TIMEOUT);
+    if join_timeout(go_thread, SHUTDOWN_TIMEOUT).is_none() {
+        println!("The controller thread did not stop in time");
+    }
+
+    if let Some(Ok(Err(e))) = result {
+        println!("Giving up, {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn port_arg<'a>(name: &'a str, help: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(name)
+        .help(help)
+        .validator(|val| val.parse::<u16>().map(|_| ()).map_err(|e| format!("Unable to parse port: {}", e)))
+        .takes_value(true)
+}
+
+fn port_value(matches: &ArgMatches, name: &str, default: BaseProtocol) -> u16 {
+    matches.value_of(name).map(|val| val.parse::<u16>().unwrap()).unwrap_or_else(|| default.into())
+}
+
+/// Runs the emulator until `quit` or the end of stdin, taking commands from stdin meanwhile.
+fn run_emulator(config: emulator::EmulatorConfig) {
+    let emulator = match emulator::start(config) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("[Emulator] Unable to start: {}", e);
+            return;
+        }
+    };
+
+    println!("### Commands: rumble <handle>, stop <handle>, list, quit ###");
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(val) => val,
+            Err(_) => break
+        };
+
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next().map(|val| val.parse::<i32>())) {
+            (Some("rumble"), Some(Ok(handle))) => emulator.rumble(handle, true),
+            (Some("stop"), Some(Ok(handle))) => emulator.rumble(handle, false),
+            (Some("list"), None) => {
+                println!("[Emulator] Client: {:?}", emulator.client());
+                for device in emulator.devices() {
+                    println!("[Emulator] handle={} vid={:#06x} pid={:#06x} device_slot={} pad_slot={} data={:02x?}",
+                        device.handle, device.vid, device.pid, device.device_slot, device.pad_slot, device.last_data);
+                }
+            },
+            (Some("quit"), None) => break,
+            (None, _) => {},
+            _ => println!("[Emulator] Unknown command: {}", line)
+        }
+    }
+
+    emulator.stop();
+}
+
+fn print_status(application_state: &SharedState, latency: &Mutex<LatencyStats>, network: &NetworkHandle, input: &InputState) {
+    println!("[Status] {:?}, {}, {}", application_state.get(), latency.lock().unwrap(), network.frame_stats());
+    for controller in input.controllers.lock().unwrap().iter() {
+        println!("[Status] {}", controller);
+    }
+}
+
+/// Waits for `thread` to end, at most for `timeout`. `None` when it is still running.
+fn join_timeout<T>(thread: JoinHandle<T>, timeout: Duration) -> Option<std::thread::Result<T>> {
+    let deadline = Instant::now() + timeout;
+    while !thread.is_finished() {
+        if Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Some(thread.join())
+}
+
+fn parse_count(val: &str) -> Result<u32, String> {
+    match val.parse::<u32>() {
+        Ok(0) => Err("Count must be at least 1".to_owned()),
+        Ok(val) => Ok(val),
+        Err(e) => Err(format!("Unable to parse count: {}", e))
+    }
+}
+
+fn parse_mtu(val: &str) -> Result<u16, String> {
+    match val.parse::<u16>() {
+        // the smallest MTU IPv4 allows, still fits one controller after the headers
+        Ok(val) if val < 68 => Err("MTU must be at least 68".to_owned()),
+        Ok(val) => Ok(val),
+        Err(e) => Err(format!("Unable to parse MTU: {}", e))
+    }
+}
+
+fn parse_impairment(val: &str) -> Result<ImpairmentConfig, String> {
+    let mut config = ImpairmentConfig::default();
+    for setting in val.split(',') {
+        let (name, value) = setting.split_once('=').ok_or_else(|| format!("Expected NAME=VALUE, got {}", setting))?;
+        let value = value.parse::<u32>().map_err(|e| format!("Unable to parse {}: {}", name, e))?;
+        let percent = || if value <= 100 { Ok(value as f64 / 100.0) } else { Err(format!("{} must be a percentage", name)) };
+        match name {
+            "drop" => config.drop = percent()?,
+            "duplicate" => config.duplicate = percent()?,
+            "reorder" => config.reorder = percent()?,
+            "delay" => config.delay = Duration::from_millis(value as u64),
+            "jitter" => config.jitter = Duration::from_millis(value as u64),
+            "disconnect" if value > 0 => config.disconnect = Some(Duration::from_secs(value as u64)),
+            "disconnect" => return Err("disconnect must be at least 1 second".to_owned()),
+            _ => return Err(format!("Unknown impairment {}, expected drop, delay, jitter, duplicate, reorder or disconnect", name))
+        }
+    }
+    Ok(config)
+}
+
+fn parse_device_id(val: &str) -> Result<DeviceIdOverride, String> {
+    let (device, ids) = val.rsplit_once('=').ok_or_else(|| format!("Expected DEVICE=VID:PID, got {}", val))?;
+    let (vid, pid) = ids.split_once(':').ok_or_else(|| format!("Expected VID:PID, got {}", ids))?;
+    let parse = |id: &str| u16::from_str_radix(id.trim_start_matches("0x"), 16).map_err(|e| format!("Unable to parse device ID {}: {}", id, e));
+
+    Ok(DeviceIdOverride {
+        device: device.to_owned(),
+        vid: parse(vid)?,
+        pid: parse(pid)?
+    })
 }
 
 struct Timer {
+    #[cfg_attr(not(windows), allow(dead_code))]
     value: u32
 }
 
@@ -1,16 +1,21 @@
+use std::{net::IpAddr, path::PathBuf, sync::{Mutex, atomic::AtomicBool}, time::Duration};
+
 use flume::Sender;
 use gilrs::{GamepadId, ff::Effect};
 
-use crate::commands::Command;
+use crate::{attach::ControllerStatus, controller_manager::REPORT_SIZE, impairment::ImpairmentConfig, protocol::{ConfigStatus, UserDataStatus}, reconnect::ReconnectPolicy};
 
 pub struct Controller {
     pub id: GamepadId,
     pub handle: i32,
     pub device_slot: i16,
     pub pad_slot: i8,
-    pub effect: Option<Effect>
+    pub effect: Option<Effect>,
+    /// Input last polled, `None` until polled after attaching.
+    pub report: Option<[u8; REPORT_SIZE]>
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum TcpProtocol {
     TcpCommandAttach = 0x01,
     TcpCommandDetach = 0x02,
@@ -23,15 +28,15 @@ pub enum TcpProtocol {
     TcpCommandAttachUserdataBad = 0xE9
 }
 
-impl Into<u16> for TcpProtocol {
-    fn into(self) -> u16 {
-        self as u16
+impl From<TcpProtocol> for u16 {
+    fn from(val: TcpProtocol) -> Self {
+        val as u16
     }
 }
 
-impl Into<u8> for TcpProtocol {
-    fn into(self) -> u8 {
-        self as u8
+impl From<TcpProtocol> for u8 {
+    fn from(val: TcpProtocol) -> Self {
+        val as u8
     }
 }
 
@@ -40,56 +45,207 @@ pub enum UdpProtocol {
     UdpCommandRumble = 0x01
 }
 
-impl Into<u16> for UdpProtocol {
-    fn into(self) -> u16 {
-        self as u16
+impl From<UdpProtocol> for u16 {
+    fn from(val: UdpProtocol) -> Self {
+        val as u16
     }
 }
 
-impl Into<u8> for UdpProtocol {
-    fn into(self) -> u8 {
-        self as u8
+impl From<UdpProtocol> for u8 {
+    fn from(val: UdpProtocol) -> Self {
+        val as u8
     }
 }
 
-pub enum TcpMessage {
-    Attach(AttachData),
-    Detach(DetachData),
-    Ping(Sender<PingResponse>)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProtocolVersion {
+    Unknown = 0x00,
+    Version1 = 0x12,
+    Version2 = 0x13,
+    Version3 = 0x14,
+    Abort = 0x30
+}
+
+impl ProtocolVersion {
+    /// Picks the version we answer with when the server offers `offered`: a known version as-is, anything else aborts.
+    pub fn negotiate(offered: u8) -> Option<ProtocolVersion> {
+        match ProtocolVersion::from(offered) {
+            ProtocolVersion::Unknown | ProtocolVersion::Abort => None,
+            version => Some(version)
+        }
+    }
+}
+
+impl From<ProtocolVersion> for u8 {
+    fn from(val: ProtocolVersion) -> Self {
+        val as u8
+    }
+}
+
+impl From<u8> for ProtocolVersion {
+    fn from(val: u8) -> Self {
+        match val {
+            0x12 => ProtocolVersion::Version1,
+            0x13 => ProtocolVersion::Version2,
+            0x14 => ProtocolVersion::Version3,
+            0x30 => ProtocolVersion::Abort,
+            _ => ProtocolVersion::Unknown
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum BaseProtocol {
+    //Version = ProtocolVersion::Version3 as isize,
+
+    TcpPort = 8112,
+    UdpPort = 8113,
+    UdpServerPort = 8114,
+}
+
+impl From<BaseProtocol> for i16 {
+    fn from(val: BaseProtocol) -> Self {
+        val as i16
+    }
+}
+
+impl From<BaseProtocol> for u16 {
+    fn from(val: BaseProtocol) -> Self {
+        val as u16
+    }
+}
+
+impl From<BaseProtocol> for u8 {
+    fn from(val: BaseProtocol) -> Self {
+        val as u8
+    }
+}
+
+/// IDs announced when no real or overridden ones are used, the server never has a config for them.
+pub const GENERIC_VID: i16 = 0x7331;
+pub const GENERIC_PID: i16 = 0x1337;
+
+/// When controller data is sent to the Wii U.
+#[derive(Clone, Copy)]
+pub enum SendMode {
+    /// Every poll, at the polling rate.
+    Fixed,
+    /// Only when the input of a controller changed, and again after `keepalive` in case the last packet was lost.
+    /// Each change is repeated `copies` times, at least `spacing` apart, for links that lose packets.
+    OnChange { keepalive: Duration, copies: u32, spacing: Duration }
 }
 
-pub enum UdpMessage {
-    UdpData(Box<dyn Command>)
+/// How controllers are polled, attached and sent to the Wii U.
+pub struct ControllerConfig {
+    pub polling_rate: u32,
+    pub send_mode: SendMode,
+    pub device_ids: DeviceIds,
+    /// File the handles of the gamepads seen are kept in, `None` to not keep them.
+    pub registry: Option<PathBuf>
 }
 
-pub enum PingResponse {
-    Pong,
-    Disconnect
+/// Shared by the input loop with the command line.
+#[derive(Default)]
+pub struct InputState {
+    pub paused: AtomicBool,
+    /// Every gamepad and how attaching it went, updated when that changes.
+    pub controllers: Mutex<Vec<ControllerStatus>>
+}
+
+/// Which vendor/product IDs are announced when attaching.
+pub struct DeviceIds {
+    pub use_real: bool,
+    pub overrides: Vec<DeviceIdOverride>
+}
+
+/// Fixed IDs for a gamepad, matched by its name or UUID.
+pub struct DeviceIdOverride {
+    pub device: String,
+    pub vid: u16,
+    pub pid: u16
+}
+
+/// Where the Wii U is and which local sockets are used to talk to it.
+#[derive(Clone)]
+pub struct NetworkConfig {
+    pub host: String,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+    /// Local port controller data is sent from.
+    pub data_port: u16,
+    /// Local port the Wii U sends rumble to.
+    pub rumble_port: u16,
+    pub bind_address: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub reconnect: ReconnectPolicy,
+    /// Deadline for connecting and for every read or write on the control stream.
+    pub control_timeout: Duration,
+    /// How long a pong may take before the ping counts as missed.
+    pub ping_timeout: Duration,
+    /// Missed pongs in a row after which the connection is considered dead.
+    pub max_missed_pongs: u32,
+    /// Largest IP packet controller data is sent in, bigger frames are split.
+    pub mtu: u16,
+    pub impairment: Option<ImpairmentConfig>,
+    /// Logs every exchange with the Wii U.
+    pub trace: bool,
+    /// pcap file the exchanges with the Wii U are written to.
+    pub capture: Option<PathBuf>
+}
+
+pub enum TcpMessage {
+    Attach(AttachData),
+    Detach(DetachData)
 }
 
 pub struct AttachData {
     pub handle: i32,
-    pub response: Sender<Option<AttachResponse>>
+    pub vid: i16,
+    pub pid: i16,
+    pub response: Sender<AttachOutcome>
 }
 
 pub struct DetachData {
-    pub handle: i32
+    pub handle: i32,
+    /// Device and pad slot of a controller input was sent for, it is released on the console right before detaching.
+    pub release: Option<(i16, i8)>
 }
 
 pub struct AttachResponse {
+    pub config: ConfigStatus,
+    pub user_data: UserDataStatus,
     pub device_slot: i16,
     pub pad_slot: i8
 }
 
+/// What happened to a single attach request. Only `Io` means the control stream is gone.
+pub enum AttachOutcome {
+    Attached(AttachResponse),
+    InvalidSlot { device_slot: i16, pad_slot: i8 },
+    UnexpectedByte(u8),
+    Io(std::io::Error)
+}
+
 pub enum Rumble {
     Start(i32),
     Stop(i32)
 }
 
-#[derive(Clone, Copy)]
+/// Which way bytes travel, seen from the client.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Outgoing,
+    Incoming
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ApplicationState {
     Disconnected,
     Connected,
+    /// Asked to exit, controllers are being released and detached before the connection closes.
+    ShuttingDown,
     Exiting
 }
 
@@ -98,11 +254,43 @@ impl ApplicationState {
         matches!(*self, ApplicationState::Exiting)
     }
 
+    pub const fn is_shutting_down(&self) -> bool {
+        matches!(*self, ApplicationState::ShuttingDown)
+    }
+
+    /// Shutting down or already exiting.
+    pub const fn is_stopping(&self) -> bool {
+        matches!(*self, ApplicationState::ShuttingDown | ApplicationState::Exiting)
+    }
+
     pub const fn is_disconnected(&self) -> bool {
         matches!(*self, ApplicationState::Disconnected)
     }
+}
 
-    pub const fn is_connected(&self) -> bool {
-        matches!(*self, ApplicationState::Connected)
+#[cfg(test)]
+mod tests {
+    use super::ProtocolVersion;
+
+    #[test]
+    fn negotiate_accepts_known_versions() {
+        assert_eq!(ProtocolVersion::negotiate(0x12), Some(ProtocolVersion::Version1));
+        assert_eq!(ProtocolVersion::negotiate(0x13), Some(ProtocolVersion::Version2));
+        assert_eq!(ProtocolVersion::negotiate(0x14), Some(ProtocolVersion::Version3));
+    }
+
+    #[test]
+    fn negotiate_rejects_everything_else() {
+        for offered in (0..=u8::MAX).filter(|val| !(0x12..=0x14).contains(val)) {
+            assert_eq!(ProtocolVersion::negotiate(offered), None, "offered {:#04x}", offered);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn versions_round_trip_through_bytes() {
+        for version in [ProtocolVersion::Version1, ProtocolVersion::Version2, ProtocolVersion::Version3, ProtocolVersion::Abort] {
+            assert_eq!(ProtocolVersion::from(u8::from(version)), version);
+        }
+        assert_eq!(ProtocolVersion::from(0x15), ProtocolVersion::Unknown);
+    }
+}
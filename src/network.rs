@@ -1,473 +1,1101 @@
-use std::{net::{IpAddr, SocketAddr, TcpStream, UdpSocket}, sync::Arc, thread::{self, JoinHandle}, time::Duration};
-use std::io::Write;
-use bytebuffer::ByteBuffer;
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
-use flume::{Receiver, Sender};
-use atomic::{Atomic, Ordering};
-
-use crate::{commands::{AttachCommand, Command, DetachCommand, PingCommand}, models::{ApplicationState, AttachResponse, PingResponse, Rumble, TcpMessage, TcpProtocol, UdpMessage, UdpProtocol}};
-
-pub fn start_thread(
-    wiiu_ip: IpAddr,
-    tcp_command_sender: Sender<TcpMessage>,
-    control_receiver: Receiver<TcpMessage>,
-    controller_receiver: Receiver<UdpMessage>,
+use std::{collections::VecDeque, net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket}, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use std::{fmt, io::{ErrorKind, Read, Write}};
+use byteorder::ReadBytesExt;
+use flume::{Receiver, Sender, TrySendError};
+use mio::{Events, Interest, Poll, Token, Waker};
+use socket2::{Domain, SockRef, Socket, Type};
+
+use crate::{capture::Capture, commands::{AttachCommand, Command, DetachCommand, Frame, PingCommand}, impairment::Impairment, latency::LatencyStats, models::{ApplicationState, AttachData, Direction, AttachOutcome, AttachResponse, NetworkConfig, ProtocolVersion, Rumble, TcpMessage}, protocol::{AttachReply, ControlRequest, DataPacket, DecodeError, Message, Pong, RumblePacket}, reconnect::{Backoff, ReconnectError}, safety::Safety, state::SharedState, trace::{Channel, Tracer}, triple_buffer::{Reader, Writer, triple_buffer}};
+
+const CONTROL: Token = Token(0);
+const RUMBLE: Token = Token(1);
+const WAKER: Token = Token(2);
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const UDP_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// IPv6 and UDP headers, the most that can come in front of the data within the MTU.
+const DATAGRAM_OVERHEAD: usize = 48;
+
+/// Lets other threads hand messages to the network loop, waking it up for each.
+#[derive(Clone)]
+pub struct NetworkHandle {
+    tcp_sender: Sender<TcpMessage>,
+    frame_stats: Arc<FrameStats>,
+    waker: Arc<Waker>
+}
+
+/// What happened to the controller frames, for the status output.
+#[derive(Default)]
+pub struct FrameStats {
+    /// Frames replaced by a newer one before the network loop got to send them.
+    dropped: AtomicU64,
+    /// Redundant copies of changed frames that were sent.
+    copies: AtomicU64,
+    /// Changes that only got out through a copy, because the frame itself was dropped or failed to send.
+    rescued: AtomicU64
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} frames dropped, {} copies sent, {} changes only sent by a copy",
+            self.dropped.load(Ordering::Relaxed), self.copies.load(Ordering::Relaxed), self.rescued.load(Ordering::Relaxed))
+    }
+}
+
+impl NetworkHandle {
+    pub fn send_tcp(&self, message: TcpMessage) -> Result<(), flume::SendError<TcpMessage>> {
+        self.tcp_sender.send(message)?;
+        let _ = self.waker.wake();
+        Ok(())
+    }
+
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.frame_stats
+    }
+}
+
+/// Hands controller frames to the network loop. Sending never waits, only the latest frame is sent.
+pub struct FrameSender {
+    writer: Writer<Frame>,
+    max_datagram: usize,
+    frame_stats: Arc<FrameStats>,
+    waker: Arc<Waker>
+}
+
+impl FrameSender {
+    /// Largest datagram that fits the configured MTU.
+    pub fn max_datagram(&self) -> usize {
+        self.max_datagram
+    }
+
+    /// Lets `encode` write the frame into a reused buffer, replacing the previous frame if it was not sent yet.
+    /// Nothing is sent when `encode` fails.
+    pub fn send<E>(&mut self, encode: impl FnOnce(&mut Frame) -> Result<(), E>) -> Result<(), E> {
+        let frame = self.writer.back();
+        frame.clear();
+        encode(frame)?;
+
+        if !self.writer.publish() {
+            self.frame_stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        let _ = self.waker.wake();
+        Ok(())
+    }
+}
+
+/// Ends with an error when the reconnect policy gave up.
+pub type NetworkThread = JoinHandle<Result<(), ReconnectError>>;
+
+/// Starts the network loop, which owns every socket talking to the Wii U.
+pub fn start(
+    config: NetworkConfig,
     reconnection_sender: Sender<()>,
     rumble_sender: Sender<Rumble>,
-    application_state: Arc<Atomic<ApplicationState>>
-) -> JoinHandle<()> {
-    thread::spawn({
-        move || {
-            let ping_interval = Duration::from_secs(1);
-
-            let control_thread = start_control_thread(control_receiver.clone(), reconnection_sender, wiiu_ip, application_state.clone());
-
-            let controller_thread = start_controller_thread(controller_receiver, wiiu_ip, application_state.clone());
-
-            let rumble_thread = start_rumble_thread(rumble_sender, wiiu_ip, application_state.clone());
-
-            loop {
-                match application_state.load(Ordering::Relaxed) {
-                    ApplicationState::Disconnected => {
-                        thread::sleep(Duration::from_secs(1));
-                        continue;
-                    },
-                    ApplicationState::Exiting => break,
-                    ApplicationState::Connected => {}
-                }
+    application_state: Arc<SharedState>,
+    latency: Arc<Mutex<LatencyStats>>
+) -> std::io::Result<(NetworkHandle, FrameSender, NetworkThread)> {
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
 
-                let (s, r) = flume::bounded(0);
-                match tcp_command_sender.send_timeout(TcpMessage::Ping(s), ping_interval) {
-                    Ok(_) => {
-                        let _ = r.recv_timeout(ping_interval);
-                    },
-                    Err(_) => {}
-                };
-                thread::sleep(ping_interval);
+    // exiting has to interrupt a poll that might otherwise sleep until the next ping
+    application_state.on_change({
+        let waker = waker.clone();
+        move |_| {
+            let _ = waker.wake();
+        }
+    });
+
+    let (tcp_sender, tcp_receiver) = flume::unbounded();
+    let (writer, frames) = triple_buffer();
+    let frame_stats = Arc::new(FrameStats::default());
+    let handle = NetworkHandle { tcp_sender, frame_stats: frame_stats.clone(), waker: waker.clone() };
+    let frame_sender = FrameSender { writer, max_datagram: config.mtu as usize - DATAGRAM_OVERHEAD, frame_stats: frame_stats.clone(), waker: waker.clone() };
+
+    let capture = match &config.capture {
+        Some(path) => Some(Capture::create(path)?),
+        None => None
+    };
+
+    let mut network = Network {
+        backoff: Backoff::new(config.reconnect.clone()),
+        impairment: config.impairment.clone().map(Impairment::new),
+        tracer: Tracer::new(config.trace, capture),
+        config,
+        poll,
+        tcp_receiver,
+        frames,
+        frame_stats,
+        sent_change: 0,
+        safety: Safety::default(),
+        reconnection_sender,
+        rumble_sender,
+        application_state,
+        latency,
+        waker,
+        control: Control::Idle { retry_at: Instant::now() },
+        was_connected: false,
+        wiiu_ip: None,
+        data_socket: None,
+        rumble_socket: None,
+        udp_retry_at: None,
+        ping: PingCommand::new(),
+        read_buffer: [0; 1400]
+    };
+
+    let thread = thread::spawn(move || network.run());
+    Ok((handle, frame_sender, thread))
+}
+
+enum Control {
+    /// Waiting for the next connection attempt.
+    Idle { retry_at: Instant },
+    /// Looking the host up on another thread, so the loop keeps running while the lookup blocks.
+    Resolving { addrs: Receiver<std::io::Result<Vec<SocketAddr>>>, deadline: Instant },
+    Connecting { link: Link, addr: SocketAddr, remaining: VecDeque<SocketAddr>, deadline: Instant },
+    Handshaking { link: Link, addr: SocketAddr, remaining: VecDeque<SocketAddr>, requested: Option<ProtocolVersion>, deadline: Instant },
+    Connected(Connection)
+}
+
+/// Non-blocking control stream with the bytes not yet decoded or sent.
+struct Link {
+    stream: mio::net::TcpStream,
+    input: Vec<u8>,
+    output: Vec<u8>
+}
+
+impl Link {
+    /// Reads everything available, returns false once the server closed the connection.
+    fn receive(&mut self, buffer: &mut [u8]) -> std::io::Result<bool> {
+        loop {
+            match self.stream.read(buffer) {
+                Ok(0) => return Ok(false),
+                Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
             }
+        }
+    }
 
-            let _ = controller_thread.join();
-            let _ = rumble_thread.join();
-            let _ = control_thread.join();
+    /// Writes as much of the output as the socket takes right now.
+    fn flush(&mut self) -> std::io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(std::io::Error::new(ErrorKind::WriteZero, "connection closed")),
+                Ok(count) => { self.output.drain(..count); },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
         }
-    })
+        Ok(())
+    }
+
+    /// Blocks until all output is written or `timeout` passed, for the last messages before closing.
+    fn flush_blocking(&mut self, timeout: Duration) -> std::io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let socket = SockRef::from(&self.stream);
+        socket.set_nonblocking(false)?;
+        while !self.output.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(std::io::Error::new(ErrorKind::TimedOut, "the server is not taking any data"));
+            }
+
+            socket.set_write_timeout(Some(remaining))?;
+            match socket.send(&self.output) {
+                Ok(0) => return Err(std::io::Error::new(ErrorKind::WriteZero, "connection closed")),
+                Ok(count) => { self.output.drain(..count); },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {},
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Connection {
+    link: Link,
+    /// Replies the server still owes, in the order they will arrive.
+    pending: VecDeque<Pending>,
+    next_ping: Instant,
+    missed_pongs: u32,
+    /// Set while output is stuck in the socket, the server has until then to take it.
+    write_deadline: Option<Instant>
 }
 
-fn start_control_thread(
-    receiver: Receiver<TcpMessage>,
-    reconnection_notifier: Sender<()>,
-    wiiu_ip: IpAddr,
-    application_state: Arc<Atomic<ApplicationState>>
-) -> JoinHandle<()> {
-    thread::spawn(move || {
-        let timeout = Duration::from_secs(1);
-        let ping = PingCommand::new();
-        let mut stream = TcpConnectionResult::Bad;
+enum Pending {
+    /// `missed` pongs are past their deadline, but still have to be read before newer replies.
+    Pong { sent: Instant, deadline: Instant, missed: bool },
+    Attach { request: String, response: Sender<AttachOutcome>, deadline: Instant }
+}
+
+impl Pending {
+    fn deadline(&self) -> Option<Instant> {
+        match self {
+            Pending::Pong { missed: true, .. } => None,
+            Pending::Pong { deadline, .. } | Pending::Attach { deadline, .. } => Some(*deadline)
+        }
+    }
+}
+
+/// Why the control connection has to be made again.
+enum Lost {
+    Message(String),
+    Closed
+}
+
+struct Network {
+    config: NetworkConfig,
+    poll: Poll,
+    tcp_receiver: Receiver<TcpMessage>,
+    frames: Reader<Frame>,
+    frame_stats: Arc<FrameStats>,
+    /// `Frame::change` of the last frame that was sent.
+    sent_change: u64,
+    safety: Safety,
+    reconnection_sender: Sender<()>,
+    rumble_sender: Sender<Rumble>,
+    application_state: Arc<SharedState>,
+    latency: Arc<Mutex<LatencyStats>>,
+    /// Wakes the loop up once a lookup finished.
+    waker: Arc<Waker>,
+    backoff: Backoff,
+    control: Control,
+    was_connected: bool,
+    /// Address the control stream reached last, the UDP sockets follow it.
+    wiiu_ip: Option<IpAddr>,
+    data_socket: Option<UdpSocket>,
+    rumble_socket: Option<mio::net::UdpSocket>,
+    udp_retry_at: Option<Instant>,
+    ping: PingCommand,
+    /// Simulated network trouble, only when asked for.
+    impairment: Option<Impairment>,
+    tracer: Tracer,
+    read_buffer: [u8; 1400]
+}
+
+impl Network {
+    fn run(&mut self) -> Result<(), ReconnectError> {
+        let mut events = Events::with_capacity(16);
         loop {
-            if application_state.load(Ordering::Relaxed).is_exiting() {
-                match stream {
-                    TcpConnectionResult::Bad => {}
-                    TcpConnectionResult::Good(ref mut stream) => {
-                        close(stream);
-                    }
+            if self.application_state.get().is_exiting() {
+                // the last frame and detaches are handed over right before exiting
+                self.send_frame();
+                self.release_controllers();
+                self.handle_tcp_messages()?;
+                self.close();
+                return Ok(());
+            }
+
+            self.handle_timers(Instant::now())?;
+
+            let timeout = self.next_deadline().map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() != ErrorKind::Interrupted {
+                    println!("[Network] Unable to wait for events: {}", e);
+                    thread::sleep(UDP_RETRY_DELAY);
                 }
-                return;
-            }
-
-            match stream {
-                TcpConnectionResult::Good(ref mut tcp_stream) => {
-                    match receiver.recv_timeout(timeout) {
-                        Ok(action) => {
-                            match action {
-                                TcpMessage::Ping(sender) => {
-                                    //println!("Ping!");
-                                    match tcp_stream.write_all(ping.byte_data()) {
-                                        Err(e) => {
-                                            println!("[Control] Unable to ping :(. Reconnecting... Error: {}", e);
-                                            stream = TcpConnectionResult::Bad;
-                                            let _ = sender.send_timeout(PingResponse::Disconnect, timeout);
-                                        }
-                                        Ok(_) => {
-                                            match tcp_stream.read_u8() {
-                                                Ok(val) => {
-                                                    if val == TcpProtocol::TcpCommandPong.into() {
-                                                        //println!("Pong!");
-                                                        let _ = sender.send_timeout(PingResponse::Pong, timeout);
-                                                    } else {
-                                                        stream = TcpConnectionResult::Bad;
-                                                        let _ = sender.send_timeout(PingResponse::Disconnect, timeout);
-                                                    }
-                                                },
-                                                Err(_) => {
-                                                    stream = TcpConnectionResult::Bad;
-                                                    let _ = sender.send_timeout(PingResponse::Disconnect, timeout);
-                                                }
-                                            }
-                                        }
-                                    };
-                                }
-                                TcpMessage::Attach(attach_data) => {
-                                    let attached = attach_controller(attach_data.handle, tcp_stream);
-                                    if attached.is_none() {
-                                        stream = TcpConnectionResult::Bad; // not quite, needs to make it better
-                                    }
-                                    let _r =
-                                        attach_data.response.send(attached);
-                                }
-                                TcpMessage::Detach(detach_data) => {
-                                    if !detach_controller(detach_data.handle, tcp_stream) {
-                                        stream = TcpConnectionResult::Bad; // not quite, needs to make it better
-                                    }
-                                }
-                            }
-        
-                        }
-                        Err(_) => {}
-                    };
-                },
-                TcpConnectionResult::Bad => {
-                    // everyone should disconnect and wait for reconnection command
-                    application_state.store(ApplicationState::Disconnected, Ordering::SeqCst);
-                    stream = tcp_connect(wiiu_ip);
-                    match stream {
-                        TcpConnectionResult::Bad => {
-                            println!("[Control] Unable to connect, waiting 2 seconds to try again");
-                            thread::sleep(Duration::from_secs(2));
-                        },
-                        TcpConnectionResult::Good(_) => {
-                            // everyone can reconnect
-                            let _ = reconnection_notifier.send(());
-                            let _ = application_state.compare_exchange(
-                                ApplicationState::Disconnected, ApplicationState::Connected,
-                                Ordering::SeqCst, Ordering::Relaxed);
-                        }
-                    }
+                continue;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    CONTROL => self.handle_control()?,
+                    RUMBLE => self.receive_rumble(),
+                    _ => {}
                 }
             }
+
+            // channels are drained on every wake up, a message may have come in together with another event
+            // the frame goes first, a controller is released before it is detached
+            self.send_frame();
+            self.handle_tcp_messages()?;
         }
-    })
-}
+    }
 
-fn start_controller_thread(
-    command_receiver: Receiver<UdpMessage>,
-    wiiu_ip: IpAddr,
-    application_state: Arc<Atomic<ApplicationState>>
-) -> JoinHandle<()> {
-    thread::spawn(move || {
-        let mut udp_socket: Option<UdpSocket> = None;
-        let receive_timeout = Duration::from_secs(1);
-        loop {
-            match application_state.load(Ordering::Relaxed) {
-                ApplicationState::Disconnected => {
-                    thread::sleep(Duration::from_secs(1));
-                    continue;
+    /// The earliest time something has to happen without any event coming in.
+    fn next_deadline(&self) -> Option<Instant> {
+        let control = match &self.control {
+            Control::Idle { retry_at } => Some(*retry_at),
+            Control::Resolving { deadline, .. } | Control::Connecting { deadline, .. } | Control::Handshaking { deadline, .. } => Some(*deadline),
+            Control::Connected(connection) => {
+                connection.pending.iter().filter_map(Pending::deadline)
+                    .chain(Some(connection.next_ping))
+                    .chain(connection.write_deadline)
+                    .min()
+            }
+        };
+
+        control.into_iter()
+            .chain(self.udp_retry_at)
+            .chain(self.impairment.as_ref().and_then(Impairment::next_deadline))
+            .min()
+    }
+
+    fn handle_timers(&mut self, now: Instant) -> Result<(), ReconnectError> {
+        self.release_impaired(now);
+        if let Some(impairment) = &mut self.impairment {
+            if impairment.disconnect_due(matches!(self.control, Control::Connected(_)), now) {
+                return self.lose_connection(Lost::Message("Impairment dropped the connection".to_owned()), now);
+            }
+        }
+
+        if self.udp_retry_at.map(|retry_at| retry_at <= now).unwrap_or(false) {
+            self.udp_retry_at = None;
+            self.open_udp_sockets(now);
+        }
+
+        let lost = match &mut self.control {
+            Control::Idle { retry_at } if *retry_at <= now => return self.connect(now),
+            Control::Idle { .. } => None,
+            Control::Resolving { addrs, deadline } => match addrs.try_recv() {
+                Ok(result) => return self.connect_to(result, now),
+                Err(flume::TryRecvError::Empty) if *deadline <= now => {
+                    println!("Unable to resolve {} in time", self.config.host);
+                    return self.connection_failed(now);
                 },
-                ApplicationState::Exiting => return,
-                ApplicationState::Connected => {}
-            }
-
-            match udp_socket {
-                Some(ref socket) => {
-                    if let Ok(val) = command_receiver.recv_timeout(receive_timeout) {
-                        match val {
-                            UdpMessage::UdpData(data) => {
-                                if let Err(e) = socket.send(data.byte_data()) {
-                                    eprintln!("[Controller] Unable to send UDP data. Dropping packet. Error: {}", e);
-                                }
-                            }
-                        };
+                Err(flume::TryRecvError::Empty) => None,
+                Err(flume::TryRecvError::Disconnected) => {
+                    println!("Unable to resolve {}: the lookup stopped", self.config.host);
+                    return self.connection_failed(now);
+                }
+            },
+            Control::Connecting { deadline, addr, .. } if *deadline <= now => {
+                println!("Couldn't connect to server at {}... timed out", addr);
+                return self.try_next_address(now);
+            },
+            Control::Handshaking { deadline, addr, requested, .. } if *deadline <= now => {
+                if requested.is_none() {
+                    println!("The server did not offer a protocol version in time.");
+                }
+                println!("Handshake with {} failed", addr);
+                return self.try_next_address(now);
+            },
+            Control::Connecting { .. } | Control::Handshaking { .. } => None,
+            Control::Connected(connection) => {
+                if connection.write_deadline.map(|deadline| deadline <= now).unwrap_or(false) {
+                    Some(Lost::Message("Unable to send to the server in time".to_owned()))
+                } else {
+                    self.check_replies(now)
+                }
+            }
+        };
+
+        if let Some(lost) = lost {
+            return self.lose_connection(lost, now);
+        }
+
+        if let Control::Connected(connection) = &mut self.control {
+            if connection.next_ping <= now {
+                let ping = &self.ping;
+                connection.link.output.extend_from_slice(ping.byte_data());
+                self.tracer.record(Channel::Control, Direction::Outgoing, ping.byte_data(), || ping.data());
+                connection.pending.push_back(Pending::Pong { sent: now, deadline: now + self.config.ping_timeout, missed: false });
+                connection.next_ping = now + PING_INTERVAL;
+                return self.flush_control(now);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles replies that did not come in time.
+    fn check_replies(&mut self, now: Instant) -> Option<Lost> {
+        let connection = match &mut self.control {
+            Control::Connected(connection) => connection,
+            _ => return None
+        };
+
+        for pending in connection.pending.iter_mut() {
+            match pending {
+                Pending::Pong { deadline, missed, .. } if !*missed && *deadline <= now => {
+                    *missed = true;
+                    connection.missed_pongs += 1;
+                    self.latency.lock().unwrap().record_missed();
+                    if connection.missed_pongs >= self.config.max_missed_pongs {
+                        return Some(Lost::Message(format!("No pong for {} pings", connection.missed_pongs)));
                     }
                 },
-                None => {
-                    udp_socket = udp_bind(BaseProtocol::UdpPort.into());
-                    match udp_socket {
-                        Some(ref socket) => {
-                            match socket.connect(SocketAddr::new(wiiu_ip, BaseProtocol::UdpPort.into())) {
-                                Ok(_) => {
-                                    match socket.set_nonblocking(true) {
-                                        Ok(_) => println!("[Controller] Set to nonblocking"),
-                                        Err(e) => println!("[Controller] Unable to set nonblocking: {}", e)
-                                    }
-                                }
-                                Err(e) => {
-                                    println!("[Controller] Unable to connect to send controller commands, trying again in 1 second: {}", e);
-                                    thread::sleep(Duration::from_secs(1));
-                                }
-                            };
-                        },
-                        None => {
-                            println!("[Controller] Unable to connect to send controller commands, trying again in 1 second");
-                            thread::sleep(Duration::from_secs(1));
-                        }
+                Pending::Attach { request, deadline, .. } if *deadline <= now => {
+                    return Some(Lost::Message(format!("Attach failed, no reply to {} in time", request)));
+                },
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Starts a new connection attempt, resolving the host again so a console that got a new address is found.
+    fn connect(&mut self, now: Instant) -> Result<(), ReconnectError> {
+        if let Ok(ip) = self.config.host.parse::<IpAddr>() {
+            return self.connect_to(Ok(vec![SocketAddr::new(ip, self.config.tcp_port)]), now);
+        }
+
+        // the lookup can block for a long time, the loop has to keep serving the other threads meanwhile
+        let (sender, addrs) = flume::bounded(1);
+        let host = self.config.host.clone();
+        let port = self.config.tcp_port;
+        let waker = self.waker.clone();
+        thread::spawn(move || {
+            // nobody is waiting anymore when the lookup took longer than the control timeout
+            if sender.send((host.as_str(), port).to_socket_addrs().map(Iterator::collect)).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+
+        self.control = Control::Resolving { addrs, deadline: now + self.config.control_timeout };
+        Ok(())
+    }
+
+    /// Connects to the addresses the host resolved to.
+    fn connect_to(&mut self, addrs: std::io::Result<Vec<SocketAddr>>, now: Instant) -> Result<(), ReconnectError> {
+        let remaining = match addrs {
+            Ok(addrs) => {
+                // a bind address only works for remote addresses of the same family
+                let bind_address = self.config.bind_address;
+                addrs.into_iter().filter(|addr| bind_address.map(|local| local.is_ipv4() == addr.is_ipv4()).unwrap_or(true)).collect()
+            },
+            Err(e) => {
+                println!("Unable to resolve {}: {}", self.config.host, e);
+                VecDeque::new()
+            }
+        };
+
+        if self.connect_next(remaining, now) {
+            Ok(())
+        } else {
+            self.connection_failed(now)
+        }
+    }
+
+    /// Starts connecting to the next address that accepts a socket, returns false when none is left.
+    fn connect_next(&mut self, mut remaining: VecDeque<SocketAddr>, now: Instant) -> bool {
+        while let Some(addr) = remaining.pop_front() {
+            match self.open_stream(addr) {
+                Ok(stream) => {
+                    self.control = Control::Connecting {
+                        link: Link { stream, input: Vec::new(), output: Vec::new() },
+                        addr,
+                        remaining,
+                        deadline: now + self.config.control_timeout
+                    };
+                    return true;
+                },
+                Err(e) => println!("Couldn't connect to server at {}... {}", addr, e)
+            }
+        }
+
+        false
+    }
+
+    fn open_stream(&self, addr: SocketAddr) -> std::io::Result<mio::net::TcpStream> {
+        let socket = new_socket(&self.config, addr.ip(), Type::STREAM, 0)?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(&addr.into()) {
+            Ok(_) => {},
+            Err(e) if connect_in_progress(&e) => {},
+            Err(e) => return Err(e)
+        }
+
+        let mut stream = mio::net::TcpStream::from_std(socket.into());
+        self.poll.registry().register(&mut stream, CONTROL, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(stream)
+    }
+
+    fn try_next_address(&mut self, now: Instant) -> Result<(), ReconnectError> {
+        let remaining = match &mut self.control {
+            Control::Connecting { remaining, .. } | Control::Handshaking { remaining, .. } => std::mem::take(remaining),
+            _ => VecDeque::new()
+        };
+
+        if self.connect_next(remaining, now) {
+            Ok(())
+        } else {
+            self.connection_failed(now)
+        }
+    }
+
+    /// Every address failed, waits as long as the reconnect policy says.
+    fn connection_failed(&mut self, now: Instant) -> Result<(), ReconnectError> {
+        match self.backoff.failed_attempt() {
+            Ok(delay) => {
+                println!("[Control] Unable to connect, waiting {:.1} seconds to try again", delay.as_secs_f64());
+                self.control = Control::Idle { retry_at: now + delay };
+                Ok(())
+            },
+            Err(e) => {
+                self.control = Control::Idle { retry_at: now };
+                self.application_state.set(ApplicationState::Exiting);
+                Err(e)
+            }
+        }
+    }
+
+    fn handle_control(&mut self) -> Result<(), ReconnectError> {
+        let now = Instant::now();
+        match &mut self.control {
+            Control::Idle { .. } | Control::Resolving { .. } => Ok(()),
+            Control::Connecting { link, addr, .. } => {
+                // the connect finished once the socket has no error and a peer
+                if let Err(e) = link.stream.take_error().and_then(|error| error.map_or(Ok(()), Err)).and_then(|_| link.stream.peer_addr()) {
+                    if e.kind() == ErrorKind::NotConnected {
+                        return Ok(());
                     }
+                    println!("Couldn't connect to server at {}... {}", addr, e);
+                    return self.try_next_address(now);
+                }
+
+                let (link, addr, remaining) = match std::mem::replace(&mut self.control, Control::Idle { retry_at: now }) {
+                    Control::Connecting { link, addr, remaining, .. } => (link, addr, remaining),
+                    _ => unreachable!()
+                };
+                if let Ok(local) = link.stream.local_addr() {
+                    self.tracer.set_endpoints(Channel::Control, local, addr);
+                }
+                self.control = Control::Handshaking { link, addr, remaining, requested: None, deadline: now + self.config.control_timeout };
+                self.handle_control()
+            },
+            Control::Handshaking { .. } => self.handshake_step(now),
+            Control::Connected(connection) => {
+                let lost = match connection.link.receive(&mut self.read_buffer) {
+                    Ok(true) => None,
+                    Ok(false) => Some(Lost::Closed),
+                    Err(e) => Some(Lost::Message(format!("Unable to read from the server. Error: {}", e)))
+                };
+
+                if let Some(lost) = self.handle_replies(now).or(lost) {
+                    return self.lose_connection(lost, now);
                 }
+                self.flush_control(now)
             }
         }
-    })
-}
+    }
 
-fn start_rumble_thread(
-    rumble_sender: Sender<Rumble>,
-    wiiu_ip: IpAddr,
-    application_state: Arc<Atomic<ApplicationState>>
-) -> JoinHandle<()> {
-    thread::spawn(move || {
-        let mut udp_socket: Option<UdpSocket> = None;
-        let mut udp_buffer = [0; 1400];
-        let send_timeout = Duration::from_secs(1);
-        loop {
-            match application_state.load(Ordering::Relaxed) {
-                ApplicationState::Disconnected => {
-                    thread::sleep(Duration::from_secs(1));
-                    continue;
+    /// Runs the version handshake as far as the received bytes allow.
+    fn handshake_step(&mut self, now: Instant) -> Result<(), ReconnectError> {
+        let tracer = &self.tracer;
+        let (link, requested) = match &mut self.control {
+            Control::Handshaking { link, requested, .. } => (link, requested),
+            _ => return Ok(())
+        };
+
+        let open = link.receive(&mut self.read_buffer).unwrap_or(false);
+
+        if requested.is_none() && !link.input.is_empty() {
+            let server_protocol_version = link.input.remove(0);
+            tracer.record(Channel::Control, Direction::Incoming, &[server_protocol_version],
+                || format!("Server offers {:?}", ProtocolVersion::from(server_protocol_version)));
+            println!("Server Version: {:?}", ProtocolVersion::from(server_protocol_version));
+
+            match ProtocolVersion::negotiate(server_protocol_version) {
+                Some(version) => {
+                    link.output.push(version.into());
+                    tracer.record(Channel::Control, Direction::Outgoing, &[version.into()], || format!("Requesting {:?}", version));
+                    *requested = Some(version);
                 },
-                ApplicationState::Exiting => return,
-                ApplicationState::Connected => {}
-            }
-
-            match udp_socket {
-                Some(ref socket) => {
-                    match socket.recv_from(&mut udp_buffer) {
-                        Ok((count, addr)) => {
-                            if count >= 6 && addr.ip() == wiiu_ip {
-                                let mut data = ByteBuffer::from_bytes(&udp_buffer);
-
-                                if data.read_u8() == UdpProtocol::UdpCommandRumble.into() {
-                                    let handle = data.read_i32();
-                                    if data.read_u8() == UdpProtocol::UdpCommandRumble.into() {
-                                        let _ = rumble_sender.send_timeout(Rumble::Start(handle), send_timeout);
-                                    } else {
-                                        let _ = rumble_sender.send_timeout(Rumble::Stop(handle), send_timeout);
-                                    }
-                                }
-    
-                                udp_buffer.fill(0);
-                            }
-                        }
-                        Err(_e) => {
-                            // reconnect? what if it times out?
-                        }
+                None => {
+                    println!("Unknown server protocol version {:#04x}, aborting.", server_protocol_version);
+                    link.output.push(ProtocolVersion::Abort.into());
+                    tracer.record(Channel::Control, Direction::Outgoing, &[ProtocolVersion::Abort.into()], || "Abort".to_owned());
+                    let _ = link.flush();
+                    return self.handshake_failed(now);
+                }
+            }
+        }
+
+        if link.flush().is_err() {
+            return self.handshake_failed(now);
+        }
+
+        let requested_version = match requested {
+            Some(val) => *val,
+            None if open => return Ok(()),
+            None => return self.handshake_failed(now)
+        };
+
+        if link.input.is_empty() {
+            return if open { Ok(()) } else { self.handshake_failed(now) };
+        }
+
+        let final_byte = link.input.remove(0);
+        let final_response = ProtocolVersion::from(final_byte);
+        tracer.record(Channel::Control, Direction::Incoming, &[final_byte], || format!("Server settles on {:?}", final_response));
+        match final_response {
+            ProtocolVersion::Unknown => {
+                println!("Something stranged happend while connecting. Try to use the newest version of HIDtoVPAD and this network client.");
+                self.handshake_failed(now)
+            },
+            ProtocolVersion::Abort => {
+                println!("The HIDtoVPAD version is not supported. Try to use the newest version of HIDtoVPAD and this network client.");
+                self.handshake_failed(now)
+            },
+            version => {
+                if version != requested_version {
+                    println!("Server settled on {:?} instead of {:?}.", version, requested_version);
+                }
+                self.connected(version, now)
+            }
+        }
+    }
+
+    fn handshake_failed(&mut self, now: Instant) -> Result<(), ReconnectError> {
+        if let Control::Handshaking { addr, .. } = &self.control {
+            println!("Handshake with {} failed", addr);
+        }
+        self.try_next_address(now)
+    }
+
+    fn connected(&mut self, version: ProtocolVersion, now: Instant) -> Result<(), ReconnectError> {
+        let link = match std::mem::replace(&mut self.control, Control::Idle { retry_at: now }) {
+            Control::Handshaking { link, .. } => link,
+            _ => unreachable!()
+        };
+
+        let peer = link.stream.peer_addr().ok().map(|addr| addr.ip());
+        println!("[Control] Connected to {} using protocol {:?}", peer.map(|ip| ip.to_string()).unwrap_or_else(|| self.config.host.clone()), version);
+
+        self.control = Control::Connected(Connection {
+            link,
+            pending: VecDeque::new(),
+            next_ping: now + PING_INTERVAL,
+            missed_pongs: 0,
+            write_deadline: None
+        });
+        self.backoff.connected();
+        self.was_connected = true;
+
+        if peer != self.wiiu_ip {
+            self.wiiu_ip = peer;
+            self.data_socket = None;
+        }
+        self.open_udp_sockets(now);
+
+        // everyone can reconnect
+        let _ = self.reconnection_sender.send(());
+        self.application_state.compare_and_set(ApplicationState::Disconnected, ApplicationState::Connected);
+
+        // requests sent while disconnected waited for this
+        self.handle_tcp_messages()
+    }
+
+    /// Decodes every complete reply, in the order the requests were sent.
+    fn handle_replies(&mut self, now: Instant) -> Option<Lost> {
+        let connection = match &mut self.control {
+            Control::Connected(connection) => connection,
+            _ => return None
+        };
+
+        while !connection.link.input.is_empty() {
+            let input = &connection.link.input;
+            let used = match connection.pending.front() {
+                Some(Pending::Pong { .. }) => {
+                    if Pong::decode(input).is_err() {
+                        self.tracer.record(Channel::Control, Direction::Incoming, input, || "Unexpected data".to_owned());
+                        return Some(Lost::Message(format!("Expected a pong, got {:#04x}", input[0])));
                     }
+                    self.tracer.record(Channel::Control, Direction::Incoming, &input[..1], || "Pong".to_owned());
+                    1
+                },
+                Some(Pending::Attach { .. }) if input.len() < AttachReply::SIZE => break,
+                Some(Pending::Attach { .. }) => {
+                    self.tracer.record(Channel::Control, Direction::Incoming, &input[..AttachReply::SIZE], || match AttachReply::decode(input) {
+                        Ok((reply, _)) => format!("{:?}", reply),
+                        Err(e) => format!("Attach reply, {}", e)
+                    });
+                    AttachReply::SIZE
                 },
                 None => {
-                    udp_socket = udp_bind(BaseProtocol::UdpServerPort.into());
-                    match udp_socket {
-                        Some(ref socket) => {
-                            let _ = socket.set_read_timeout(Some(send_timeout));
-                        },
-                        None => {
-                            println!("[Rumble] Unable to open UDP server, trying again in 1 second.");
-                            thread::sleep(Duration::from_secs(1));
-                        }
-                    }
+                    self.tracer.record(Channel::Control, Direction::Incoming, input, || "Unexpected data".to_owned());
+                    return Some(Lost::Message(format!("Unexpected data from the server: {:02x?}", input)));
                 }
+            };
+
+            match connection.pending.pop_front() {
+                Some(Pending::Pong { sent, missed: false, .. }) => {
+                    connection.missed_pongs = 0;
+                    self.latency.lock().unwrap().record(now - sent);
+                },
+                Some(Pending::Attach { response, .. }) => {
+                    let _ = response.send(attach_outcome(&connection.link.input[..used]));
+                },
+                // late pongs only had to be read
+                Some(Pending::Pong { .. }) | None => {}
             }
+            connection.link.input.drain(..used);
         }
-    })
-}
 
-fn tcp_connect(wiiu_ip: IpAddr) -> TcpConnectionResult {
-    let addr: SocketAddr = SocketAddr::new(wiiu_ip, BaseProtocol::TcpPort.into());
-    match TcpStream::connect_timeout(&addr, Duration::from_secs(2)) {
-        Ok(mut stream) => {
-            match handshake(&mut stream) {
-                HandshakeResult::Bad => println!("Handshake timeout"),
-                HandshakeResult::Good => return TcpConnectionResult::Good(stream)
-            };
+        None
+    }
+
+    fn flush_control(&mut self, now: Instant) -> Result<(), ReconnectError> {
+        let connection = match &mut self.control {
+            Control::Connected(connection) => connection,
+            _ => return Ok(())
+        };
+
+        if let Err(e) = connection.link.flush() {
+            return self.lose_connection(Lost::Message(format!("Unable to send to the server. Error: {}", e)), now);
         }
-        Err(_) => {
-            println!("Couldn't connect to server...");
+
+        connection.write_deadline = if connection.link.output.is_empty() {
+            None
+        } else {
+            Some(connection.write_deadline.unwrap_or(now + self.config.control_timeout))
+        };
+        Ok(())
+    }
+
+    fn lose_connection(&mut self, lost: Lost, now: Instant) -> Result<(), ReconnectError> {
+        match lost {
+            Lost::Message(message) => println!("[Control] {}, reconnecting...", message),
+            Lost::Closed => println!("[Control] The server closed the connection, reconnecting...")
         }
-    };
 
-    TcpConnectionResult::Bad
-}
+        if let Control::Connected(_) = self.control {
+            self.release_controllers();
+        }
 
-fn udp_bind(port: i16) -> Option<UdpSocket> {
-    let socket = match UdpSocket::bind(format!("0.0.0.0:{}", port)) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Unable to bind UDP: {}", e);
-            return None
+        if let Control::Connected(connection) = std::mem::replace(&mut self.control, Control::Idle { retry_at: now }) {
+            for pending in connection.pending {
+                if let Pending::Attach { response, .. } = pending {
+                    let _ = response.send(AttachOutcome::Io(std::io::Error::new(ErrorKind::ConnectionAborted, "connection lost")));
+                }
+            }
         }
-    };
 
-    return Some(socket);
-}
+        // everyone should disconnect and wait for reconnection command
+        self.application_state.set(ApplicationState::Disconnected);
+        if self.was_connected {
+            self.was_connected = false;
+            if let Err(e) = self.backoff.connection_lost() {
+                self.application_state.set(ApplicationState::Exiting);
+                return Err(e);
+            }
+        }
 
-fn attach_controller(controller_handle: i32, stream: &mut TcpStream) -> Option<AttachResponse> {
-    let command = AttachCommand::new(controller_handle, 0x7331, 0x1337, 1);
-    send_attach(&command, stream)
-}
+        Ok(())
+    }
 
-fn detach_controller(controller_handle: i32, stream: &mut TcpStream) -> bool {
-    let command = DetachCommand::new(controller_handle, 1);
+    fn handle_tcp_messages(&mut self) -> Result<(), ReconnectError> {
+        let now = Instant::now();
+        let connection = match &mut self.control {
+            Control::Connected(connection) => connection,
+            // they wait in the channel until connected
+            _ => return Ok(())
+        };
 
-    stream.write_all(command.byte_data()).is_ok()
-}
+        let mut sent = false;
+        for message in self.tcp_receiver.try_iter() {
+            match message {
+                TcpMessage::Attach(attach_data) => {
+                    let request = send_attach(&attach_data, &mut connection.link.output, &self.tracer);
+                    connection.pending.push_back(Pending::Attach { request, response: attach_data.response, deadline: now + self.config.control_timeout });
+                },
+                TcpMessage::Detach(detach_data) => {
+                    // straight out, unlike a frame it cannot be replaced by a newer one before it is sent
+                    if let (Some((device_slot, pad_slot)), Some(socket)) = (detach_data.release, &self.data_socket) {
+                        if let Err(e) = send_data(socket, &Safety::release_one(detach_data.handle, device_slot, pad_slot), &self.tracer) {
+                            eprintln!("[Controller] Unable to release the controller. Error: {}", e);
+                        }
+                    }
+                    let command = DetachCommand::new(detach_data.handle, 1);
+                    connection.link.output.extend_from_slice(command.byte_data());
+                    self.tracer.record(Channel::Control, Direction::Outgoing, command.byte_data(), || command.data());
+                }
+            }
+            sent = true;
+        }
 
-fn handshake(stream: &mut TcpStream) -> HandshakeResult {
-    let server_protocol_version = match stream.read_u8() {
-        Ok(val) => val,
-        Err(_e) => return HandshakeResult::Bad
-    };
-    
-    println!("Server Version: {:?}", server_protocol_version);
+        if sent {
+            self.flush_control(now)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn send_frame(&mut self) {
+        let frame = match self.frames.take() {
+            Some(val) => val,
+            None => return
+        };
+
+        let socket = match self.data_socket {
+            Some(ref socket) if matches!(self.control, Control::Connected(_)) => socket,
+            // input from while disconnected is stale by the time it could be sent
+            _ => return
+        };
+
+        let now = Instant::now();
+        let mut sent = true;
+        for datagram in frame.datagrams() {
+            let result = match self.impairment {
+                Some(ref mut impairment) => {
+                    impairment.push(Direction::Outgoing, datagram, now);
+                    Ok(())
+                },
+                None => send_data(socket, datagram, &self.tracer)
+            };
+            if let Err(e) = result {
+                eprintln!("[Controller] Unable to send UDP data. Dropping packet. Error: {}", e);
+                sent = false;
+            }
+        }
 
-    if server_protocol_version != ProtocolVersion::Version3.into() {
-        println!("We only support {:?}, aborting. Current: {:?}", ProtocolVersion::Version3, server_protocol_version);
-        return HandshakeResult::Bad;
+        if sent && frame.copy > 0 {
+            self.frame_stats.copies.fetch_add(1, Ordering::Relaxed);
+            if self.sent_change != frame.change {
+                self.frame_stats.rescued.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if sent {
+            self.sent_change = frame.change;
+            self.safety.sent(frame);
+        }
     }
 
-    match stream.write_u8(server_protocol_version) {
-        Ok(_) => {},
-        Err(_e) => return HandshakeResult::Bad
+    /// Releases whatever the last frame held on the console, for when no more input will follow.
+    fn release_controllers(&mut self) {
+        let released = self.safety.release();
+        if let Some(socket) = &self.data_socket {
+            for datagram in released {
+                if let Err(e) = send_data(socket, &datagram, &self.tracer) {
+                    eprintln!("[Controller] Unable to release the controllers. Error: {}", e);
+                }
+            }
+        }
     }
 
-    let final_response: ProtocolVersion = match stream.read_u8() {
-        Ok(val) => val,
-        Err(_e) => return HandshakeResult::Bad
-    }.into();
+    fn open_udp_sockets(&mut self, now: Instant) {
+        let current_ip = match self.wiiu_ip {
+            Some(val) => val,
+            None => return
+        };
+
+        if self.data_socket.is_none() {
+            self.data_socket = udp_bind(&self.config, current_ip, self.config.data_port).and_then(|socket| {
+                match socket.connect(SocketAddr::new(current_ip, self.config.udp_port)).and_then(|_| socket.set_nonblocking(true)) {
+                    Ok(_) => Some(socket),
+                    Err(e) => {
+                        println!("[Controller] Unable to connect to send controller commands, trying again in 1 second: {}", e);
+                        None
+                    }
+                }
+            });
 
-    match final_response {
-        ProtocolVersion::Unknown => {
-            println!("Something stranged happend while connecting. Try to use the newest version of HIDtoVPAD and this network client.");
-            return HandshakeResult::Bad;
-        },
-        ProtocolVersion::Abort => {
-            println!("The HIDtoVPAD version is not supported. Try to use the newest version of HIDtoVPAD and this network client.");
-            return HandshakeResult::Bad;
-        },
-        _ => {}
+            if let Some((local, remote)) = self.data_socket.as_ref().and_then(|socket| socket.local_addr().ok().zip(socket.peer_addr().ok())) {
+                self.tracer.set_endpoints(Channel::Data, local, remote);
+            }
+        }
+
+        // the server socket has to be of the same family as the console's address
+        if let Some(ref socket) = self.rumble_socket {
+            if socket.local_addr().map(|addr| addr.is_ipv4() != current_ip.is_ipv4()).unwrap_or(true) {
+                self.rumble_socket = None;
+            }
+        }
+
+        if self.rumble_socket.is_none() {
+            self.rumble_socket = udp_bind(&self.config, current_ip, self.config.rumble_port).and_then(|socket| {
+                let registered = socket.set_nonblocking(true).and_then(|_| {
+                    let mut socket = mio::net::UdpSocket::from_std(socket);
+                    self.poll.registry().register(&mut socket, RUMBLE, Interest::READABLE).map(|_| socket)
+                });
+                match registered {
+                    Ok(socket) => Some(socket),
+                    Err(e) => {
+                        println!("[Rumble] Unable to open UDP server, trying again in 1 second. Error: {}", e);
+                        None
+                    }
+                }
+            });
+        }
+
+        if self.data_socket.is_none() || self.rumble_socket.is_none() {
+            self.udp_retry_at = Some(now + UDP_RETRY_DELAY);
+        }
     }
 
-    return HandshakeResult::Good;
-}
+    fn receive_rumble(&mut self) {
+        let (socket, current_ip) = match (&self.rumble_socket, self.wiiu_ip) {
+            (Some(socket), Some(ip)) => (socket, ip),
+            _ => return
+        };
+
+        loop {
+            let (count, addr) = match socket.recv_from(&mut self.read_buffer) {
+                Ok(val) => val,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return
+            };
+
+            if addr.ip().to_canonical() != current_ip.to_canonical() {
+                continue;
+            }
+
+            if let Ok(local) = socket.local_addr() {
+                self.tracer.set_endpoints(Channel::Rumble, local, addr);
+            }
+            let datagram = &self.read_buffer[..count];
+            self.tracer.record(Channel::Rumble, Direction::Incoming, datagram, || match RumblePacket::decode(datagram) {
+                Ok((rumble, _)) => format!("{:?}", rumble),
+                Err(e) => format!("Rumble, {}", e)
+            });
 
-fn send_attach(command: &AttachCommand, stream: &mut TcpStream) -> Option<AttachResponse> {
-    match stream.write_all(command.byte_data()) {
-        Ok(_) => {}
-        Err(_) => return None
+            match self.impairment {
+                Some(ref mut impairment) => impairment.push(Direction::Incoming, &self.read_buffer[..count], Instant::now()),
+                None => forward_rumble(&self.rumble_sender, &self.read_buffer[..count])
+            }
+        }
     }
 
-    let config_found = match stream.read_u8() {
-        Ok(val) => val,
-        Err(_) => return None
-    };
-    if config_found == 0 {
-        println!("Failed to get byte.");
-        return None;
-    } else if config_found == TcpProtocol::TcpCommandAttachConfigNotFound.into() {
-        println!("No config found for this device.");
-    } else if config_found == TcpProtocol::TcpCommandAttachConfigFound.into() {
-        println!("Config found for this device.");
-    } else {
-        println!("Should not get this far :(");
-    }
-
-    let user_data_okay = match stream.read_u8() {
-        Ok(val) => val,
-        Err(_) => return None
-    };
-    if user_data_okay == 0 {
-        println!("Failed to get byte.");
-        return None;
-    } else if user_data_okay == TcpProtocol::TcpCommandAttachUserdataBad.into() {
-        println!("Bad user data.");
-    } else if user_data_okay == TcpProtocol::TcpCommandAttachUserdataOkay.into() {
-        println!("User data OK.");
-    } else {
-        println!("Should not get this far :(");
-    }
-
-    let device_slot = match stream.read_i16::<NetworkEndian>() {
-        Ok(val) => val,
-        Err(_) => return None
-    };
+    /// Delivers the datagrams the impairment held back until now.
+    fn release_impaired(&mut self, now: Instant) {
+        let impairment = match self.impairment {
+            Some(ref mut val) => val,
+            None => return
+        };
 
-    let padslot = match stream.read_i8() {
-        Ok(val) => val,
-        Err(_) => return None
-    };
+        while let Some(datagram) = impairment.take(Direction::Outgoing, now) {
+            if let Some(socket) = &self.data_socket {
+                if let Err(e) = send_data(socket, &datagram, &self.tracer) {
+                    eprintln!("[Controller] Unable to send UDP data. Dropping packet. Error: {}", e);
+                }
+            }
+        }
 
-    if device_slot < 0 || padslot < 0 {
-        println!("Receiving data after sending a attach failed for device ({}) failed. We need to disconnect =(", 0);
-        return None;
+        while let Some(datagram) = impairment.take(Direction::Incoming, now) {
+            forward_rumble(&self.rumble_sender, &datagram);
+        }
     }
 
-    return Some(AttachResponse { device_slot, pad_slot: padslot });
+    /// Says goodbye to the server, waiting up to the control timeout for it to take the detaches and the abort.
+    fn close(&mut self) {
+        if let Control::Connected(connection) = &mut self.control {
+            let start = connection.link.output.len();
+            ControlRequest::Abort.encode(&mut connection.link.output);
+            self.tracer.record(Channel::Control, Direction::Outgoing, &connection.link.output[start..], || "Abort".to_owned());
+            match connection.link.flush_blocking(self.config.control_timeout) {
+                Ok(_) => println!("Succesfully closed connection."),
+                Err(e) => println!("Unable to close connection: {}", e)
+            };
+        }
+
+        self.data_socket = None;
+        self.rumble_socket = None;
+    }
 }
 
-fn close(stream: &mut TcpStream) {
-    let mut buffer = [0; 1];
-    buffer[0] = ProtocolVersion::Abort.into();
-    let _ = match stream.write(&buffer) {
-        Ok(_) => println!("Succesfully closed connection."),
-        Err(e) => println!("Unable to close connection: {:?}", e)
-    };
+fn send_data(socket: &UdpSocket, datagram: &[u8], tracer: &Tracer) -> std::io::Result<()> {
+    tracer.record(Channel::Data, Direction::Outgoing, datagram, || match DataPacket::decode(datagram) {
+        Ok((packet, _)) => format!("{:?}", packet),
+        Err(e) => format!("Controller data, {}", e)
+    });
+    socket.send(datagram).map(|_| ())
 }
 
-enum HandshakeResult {
-    Bad,
-    Good
+fn forward_rumble(rumble_sender: &Sender<Rumble>, datagram: &[u8]) {
+    if let Ok((rumble, _)) = RumblePacket::decode(datagram) {
+        let rumble = if rumble.active { Rumble::Start(rumble.handle) } else { Rumble::Stop(rumble.handle) };
+        if let Err(TrySendError::Full(_)) = rumble_sender.try_send(rumble) {
+            println!("[Rumble] Too many rumble commands waiting, dropping one");
+        }
+    }
 }
 
-enum TcpConnectionResult {
-    Bad,
-    Good(TcpStream)
+/// A non-blocking connect reports that it is still going as an error.
+fn connect_in_progress(error: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if error.raw_os_error() == Some(libc::EINPROGRESS) {
+            return true;
+        }
+    }
+
+    error.kind() == ErrorKind::WouldBlock
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[repr(u8)]
-enum ProtocolVersion {
-    Unknown = 0x00,
-    Version1 = 0x12,
-    Version2 = 0x13,
-    Version3 = 0x14,
-    Abort = 0x30
+/// Connects to `addr`, reads the version the server offers and leaves again without answering.
+/// Returns the version the client would speak with it, if it is a HIDtoVPAD server at all.
+pub fn probe(addr: SocketAddr, timeout: Duration) -> Option<ProtocolVersion> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+
+    let offered = stream.read_u8().ok()?;
+    let _ = stream.shutdown(Shutdown::Both);
+    ProtocolVersion::negotiate(offered)
 }
 
-impl Into<u8> for ProtocolVersion {
-    fn into(self) -> u8 {
-        self as u8
+/// Creates a socket for talking to `remote`, bound to the configured interface and local address.
+/// Without a configured address, datagram sockets are still bound to `port` on every local address.
+fn new_socket(config: &NetworkConfig, remote: IpAddr, kind: Type, port: u16) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(SocketAddr::new(remote, 0)), kind, None)?;
+
+    if let Some(ref interface) = config.interface {
+        bind_interface(&socket, interface)?;
     }
-}
 
-impl From<u8> for ProtocolVersion {
-    fn from(val: u8) -> Self {
-        match val {
-            0x12 => ProtocolVersion::Version1,
-            0x13 => ProtocolVersion::Version2,
-            0x14 => ProtocolVersion::Version3,
-            0x30 => ProtocolVersion::Abort,
-            _ => ProtocolVersion::Unknown
+    let local: IpAddr = match (config.bind_address, remote) {
+        (Some(local), _) => local,
+        (None, _) if kind == Type::STREAM => return Ok(socket),
+        (None, IpAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+        (None, IpAddr::V6(_)) => {
+            // also receive from IPv4 consoles, whatever the platform default is
+            let _ = socket.set_only_v6(false);
+            Ipv6Addr::UNSPECIFIED.into()
         }
-    }
+    };
+
+    socket.bind(&SocketAddr::new(local, port).into())?;
+    Ok(socket)
 }
 
-#[derive(Copy, Clone)]
-enum BaseProtocol {
-    //Version = ProtocolVersion::Version3 as isize,
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_interface(socket: &Socket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
 
-    TcpPort = 8112,
-    UdpPort = 8113,
-    UdpServerPort = 8114,
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_interface(_socket: &Socket, _interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "binding to an interface is only supported on Linux"))
 }
 
-impl Into<i16> for BaseProtocol {
-    fn into(self) -> i16 {
-        self as i16
+fn udp_bind(config: &NetworkConfig, remote: IpAddr, port: u16) -> Option<UdpSocket> {
+    match new_socket(config, remote, Type::DGRAM, port) {
+        Ok(val) => Some(val.into()),
+        Err(e) => {
+            println!("Unable to bind UDP: {}", e);
+            None
+        }
     }
 }
 
-impl Into<u16> for BaseProtocol {
-    fn into(self) -> u16 {
-        self as u16
-    }
+/// Queues the attach request, returning its description for when it goes unanswered.
+fn send_attach(attach_data: &AttachData, output: &mut Vec<u8>, tracer: &Tracer) -> String {
+    let command = AttachCommand::new(attach_data.handle, attach_data.vid, attach_data.pid, 1);
+    output.extend_from_slice(command.byte_data());
+    tracer.record(Channel::Control, Direction::Outgoing, command.byte_data(), || command.data());
+    command.data()
 }
 
-impl Into<u8> for BaseProtocol {
-    fn into(self) -> u8 {
-        self as u8
+/// Turns a whole attach reply into its outcome, the stream stays usable whatever it contains.
+fn attach_outcome(buffer: &[u8]) -> AttachOutcome {
+    let reply = match AttachReply::decode(buffer) {
+        Ok((val, _)) => val,
+        Err(DecodeError::UnexpectedByte(val)) | Err(DecodeError::UnexpectedCommand(val)) => return AttachOutcome::UnexpectedByte(val),
+        Err(DecodeError::Incomplete { .. }) => return AttachOutcome::UnexpectedByte(buffer[0])
+    };
+
+    if reply.device_slot < 0 || reply.pad_slot < 0 {
+        return AttachOutcome::InvalidSlot { device_slot: reply.device_slot, pad_slot: reply.pad_slot };
     }
-}
\ No newline at end of file
+
+    AttachOutcome::Attached(AttachResponse {
+        config: reply.config,
+        user_data: reply.user_data,
+        device_slot: reply.device_slot,
+        pad_slot: reply.pad_slot
+    })
+}
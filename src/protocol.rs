@@ -0,0 +1,487 @@
+use std::{convert::{TryFrom, TryInto}, fmt};
+
+use crate::models::{ProtocolVersion, TcpProtocol, UdpProtocol};
+
+/// Typed HIDtoVPAD messages and their wire format.
+/// Nothing in here touches sockets, callers own the buffers and the IO.
+pub trait Message: Sized {
+    /// Appends the encoded message to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decodes one message from the start of `input`, returning it with the number of bytes used.
+    fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// `input` ends before the message does, `needed` is the size of the whole message when known so far.
+    Incomplete { needed: usize },
+    /// The command byte does not start the expected message.
+    UnexpectedCommand(u8),
+    /// A status byte inside the message has an unknown value.
+    UnexpectedByte(u8)
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete { needed } => write!(f, "message incomplete, {} bytes needed", needed),
+            DecodeError::UnexpectedCommand(val) => write!(f, "unexpected command {:#04x}", val),
+            DecodeError::UnexpectedByte(val) => write!(f, "unexpected byte {:#04x}", val)
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    /// A controller report longer than its one byte length field can describe.
+    ReportTooLarge(usize),
+    /// More controllers than the one byte count of a packet can describe.
+    TooManyEntries(usize),
+    /// A single controller needs a datagram of `size` bytes, but at most `max` fit the MTU.
+    ExceedsMtu { size: usize, max: usize }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::ReportTooLarge(size) => write!(f, "controller report of {} bytes is too large, at most 255 fit", size),
+            EncodeError::TooManyEntries(count) => write!(f, "{} controllers do not fit a packet, at most 255 do", count),
+            EncodeError::ExceedsMtu { size, max } => write!(f, "controller needs a datagram of {} bytes, only {} fit the MTU", size, max)
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachRequest {
+    pub handle: i32,
+    pub vid: i16,
+    pub pid: i16
+}
+
+impl Message for AttachRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(TcpProtocol::TcpCommandAttach.into());
+        out.extend_from_slice(&self.handle.to_be_bytes());
+        out.extend_from_slice(&self.vid.to_be_bytes());
+        out.extend_from_slice(&self.pid.to_be_bytes());
+    }
+
+    fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        expect_command(input, TcpProtocol::TcpCommandAttach.into())?;
+        let input = take(input, 9)?;
+        Ok((AttachRequest { handle: read_i32(&input[1..]), vid: read_i16(&input[5..]), pid: read_i16(&input[7..]) }, 9))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetachRequest {
+    pub handle: i32
+}
+
+impl Message for DetachRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(TcpProtocol::TcpCommandDetach.into());
+        out.extend_from_slice(&self.handle.to_be_bytes());
+    }
+
+    fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        expect_command(input, TcpProtocol::TcpCommandDetach.into())?;
+        let input = take(input, 5)?;
+        Ok((DetachRequest { handle: read_i32(&input[1..]) }, 5))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ping;
+
+impl Message for Ping {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(TcpProtocol::TcpCommandPing.into());
+    }
+
+    fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        expect_command(input, TcpProtocol::TcpCommandPing.into())?;
+        Ok((Ping, 1))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pong;
+
+impl Message for Pong {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(TcpProtocol::TcpCommandPong.into());
+    }
+
+    fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        expect_command(input, TcpProtocol::TcpCommandPong.into())?;
+        Ok((Pong, 1))
+    }
+}
+
+/// Anything the client may send on the control stream, as seen by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlRequest {
+    Attach(AttachRequest),
+    Detach(DetachRequest),
+    Ping,
+    /// The client is closing the connection.
+    Abort
+}
+
+impl ControlRequest {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            ControlRequest::Attach(request) => request.encode(out),
+            ControlRequest::Detach(request) => request.encode(out),
+            ControlRequest::Ping => Ping.encode(out),
+            ControlRequest::Abort => out.push(ProtocolVersion::Abort.into())
+        }
+    }
+
+    pub fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let command = *input.first().ok_or(DecodeError::Incomplete { needed: 1 })?;
+        if command == TcpProtocol::TcpCommandAttach.into() {
+            AttachRequest::decode(input).map(|(request, size)| (ControlRequest::Attach(request), size))
+        } else if command == TcpProtocol::TcpCommandDetach.into() {
+            DetachRequest::decode(input).map(|(request, size)| (ControlRequest::Detach(request), size))
+        } else if command == TcpProtocol::TcpCommandPing.into() {
+            Ok((ControlRequest::Ping, 1))
+        } else if command == ProtocolVersion::Abort.into() {
+            Ok((ControlRequest::Abort, 1))
+        } else {
+            Err(DecodeError::UnexpectedCommand(command))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigStatus {
+    Found,
+    NotFound
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserDataStatus {
+    Okay,
+    Bad
+}
+
+/// The server's answer to an `AttachRequest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachReply {
+    pub config: ConfigStatus,
+    pub user_data: UserDataStatus,
+    pub device_slot: i16,
+    pub pad_slot: i8
+}
+
+impl AttachReply {
+    pub const SIZE: usize = 5;
+}
+
+impl Message for AttachReply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match self.config {
+            ConfigStatus::Found => TcpProtocol::TcpCommandAttachConfigFound,
+            ConfigStatus::NotFound => TcpProtocol::TcpCommandAttachConfigNotFound
+        }.into());
+        out.push(match self.user_data {
+            UserDataStatus::Okay => TcpProtocol::TcpCommandAttachUserdataOkay,
+            UserDataStatus::Bad => TcpProtocol::TcpCommandAttachUserdataBad
+        }.into());
+        out.extend_from_slice(&self.device_slot.to_be_bytes());
+        out.push(self.pad_slot as u8);
+    }
+
+    fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let input = take(input, AttachReply::SIZE)?;
+
+        let config = match input[0] {
+            val if val == TcpProtocol::TcpCommandAttachConfigFound.into() => ConfigStatus::Found,
+            val if val == TcpProtocol::TcpCommandAttachConfigNotFound.into() => ConfigStatus::NotFound,
+            val => return Err(DecodeError::UnexpectedByte(val))
+        };
+
+        let user_data = match input[1] {
+            val if val == TcpProtocol::TcpCommandAttachUserdataOkay.into() => UserDataStatus::Okay,
+            val if val == TcpProtocol::TcpCommandAttachUserdataBad.into() => UserDataStatus::Bad,
+            val => return Err(DecodeError::UnexpectedByte(val))
+        };
+
+        Ok((AttachReply { config, user_data, device_slot: read_i16(&input[2..]), pad_slot: input[4] as i8 }, AttachReply::SIZE))
+    }
+}
+
+/// Input state of a single controller inside a `DataPacket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataEntry {
+    pub handle: i32,
+    pub device_slot: i16,
+    pub pad_slot: i8,
+    pub data: Vec<u8>
+}
+
+/// Controller input sent over UDP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataPacket {
+    pub entries: Vec<DataEntry>
+}
+
+impl DataPacket {
+    pub const HEADER_SIZE: usize = 2;
+    pub const MAX_ENTRIES: usize = 0xFF;
+
+    /// Starts a packet with `count` entries, each to be appended with `write_entry`.
+    /// Lets the input be encoded straight into `out` without building a `DataPacket` first.
+    pub fn write_header(count: u8, out: &mut Vec<u8>) {
+        out.push(UdpProtocol::UdpCommandData.into());
+        out.push(count);
+    }
+
+    /// Changes the entry count of an encoded packet.
+    pub fn set_count(packet: &mut [u8], count: u8) {
+        packet[1] = count;
+    }
+
+    /// Encoded size of an entry carrying `data`.
+    pub fn entry_size(data: &[u8]) -> Result<usize, EncodeError> {
+        if data.len() > 0xFF {
+            return Err(EncodeError::ReportTooLarge(data.len()));
+        }
+        Ok(8 + data.len())
+    }
+
+    pub fn write_entry(handle: i32, device_slot: i16, pad_slot: i8, data: &[u8], out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let size = u8::try_from(data.len()).map_err(|_| EncodeError::ReportTooLarge(data.len()))?;
+        out.extend_from_slice(&handle.to_be_bytes());
+        out.extend_from_slice(&device_slot.to_be_bytes());
+        out.push(pad_slot as u8);
+        out.push(size);
+        out.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Like `Message::encode`, but a packet can hold more than its length fields describe.
+    /// Nothing is appended to `out` when it fails.
+    pub fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let count = u8::try_from(self.entries.len()).map_err(|_| EncodeError::TooManyEntries(self.entries.len()))?;
+        let start = out.len();
+        DataPacket::write_header(count, out);
+        for entry in &self.entries {
+            if let Err(e) = DataPacket::write_entry(entry.handle, entry.device_slot, entry.pad_slot, &entry.data, out) {
+                out.truncate(start);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes one packet from the start of `input`, returning it with the number of bytes used.
+    pub fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        expect_command(input, UdpProtocol::UdpCommandData.into())?;
+        let count = take(input, 2)?[1];
+
+        let mut offset = 2;
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let header = take(&input[offset..], 8).map_err(|_| DecodeError::Incomplete { needed: offset + 8 })?;
+            let size = header[7] as usize;
+            let needed = offset + 8 + size;
+            if input.len() < needed {
+                return Err(DecodeError::Incomplete { needed });
+            }
+
+            entries.push(DataEntry {
+                handle: read_i32(header),
+                device_slot: read_i16(&header[4..]),
+                pad_slot: header[6] as i8,
+                data: input[offset + 8..needed].to_vec()
+            });
+            offset = needed;
+        }
+
+        Ok((DataPacket { entries }, offset))
+    }
+}
+
+/// Rumble state the server sends to the client's UDP server port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RumblePacket {
+    pub handle: i32,
+    pub active: bool
+}
+
+impl Message for RumblePacket {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(UdpProtocol::UdpCommandRumble.into());
+        out.extend_from_slice(&self.handle.to_be_bytes());
+        out.push(if self.active { 1 } else { 0 });
+    }
+
+    fn decode(input: &[u8]) -> Result<(Self, usize), DecodeError> {
+        expect_command(input, UdpProtocol::UdpCommandRumble.into())?;
+        let input = take(input, 6)?;
+        Ok((RumblePacket { handle: read_i32(&input[1..]), active: input[5] == 1 }, 6))
+    }
+}
+
+fn expect_command(input: &[u8], command: u8) -> Result<(), DecodeError> {
+    match input.first() {
+        None => Err(DecodeError::Incomplete { needed: 1 }),
+        Some(&val) if val == command => Ok(()),
+        Some(&val) => Err(DecodeError::UnexpectedCommand(val))
+    }
+}
+
+fn take(input: &[u8], size: usize) -> Result<&[u8], DecodeError> {
+    input.get(..size).ok_or(DecodeError::Incomplete { needed: size })
+}
+
+fn read_i32(input: &[u8]) -> i32 {
+    i32::from_be_bytes(input[..4].try_into().unwrap())
+}
+
+fn read_i16(input: &[u8]) -> i16 {
+    i16::from_be_bytes(input[..2].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+
+    /// Encodes `message`, checks it decodes back whole and that every shorter prefix is incomplete.
+    fn round_trip<M: Message + PartialEq + Debug>(message: M) {
+        let mut encoded = Vec::new();
+        message.encode(&mut encoded);
+        assert_eq!(M::decode(&encoded), Ok((message, encoded.len())));
+
+        for size in 0..encoded.len() {
+            assert!(matches!(M::decode(&encoded[..size]), Err(DecodeError::Incomplete { .. })), "{} of {} bytes", size, encoded.len());
+        }
+    }
+
+    fn data_packet(entries: usize, size: usize) -> DataPacket {
+        DataPacket {
+            entries: (0..entries).map(|index| DataEntry { handle: index as i32 + 1, device_slot: 2, pad_slot: -1, data: vec![0xAB; size] }).collect()
+        }
+    }
+
+    fn attach_reply() -> AttachReply {
+        AttachReply { config: ConfigStatus::NotFound, user_data: UserDataStatus::Bad, device_slot: 300, pad_slot: -1 }
+    }
+
+    #[test]
+    fn fixed_messages_round_trip() {
+        round_trip(AttachRequest { handle: -5, vid: 0x057E, pid: -2 });
+        round_trip(DetachRequest { handle: i32::MAX });
+        round_trip(Ping);
+        round_trip(Pong);
+        round_trip(attach_reply());
+        round_trip(AttachReply { config: ConfigStatus::Found, user_data: UserDataStatus::Okay, device_slot: 0, pad_slot: 3 });
+        round_trip(RumblePacket { handle: 7, active: true });
+        round_trip(RumblePacket { handle: 7, active: false });
+    }
+
+    #[test]
+    fn control_requests_round_trip() {
+        let requests = [
+            ControlRequest::Attach(AttachRequest { handle: 1, vid: 2, pid: 3 }),
+            ControlRequest::Detach(DetachRequest { handle: 4 }),
+            ControlRequest::Ping,
+            ControlRequest::Abort
+        ];
+        for request in requests {
+            let mut encoded = Vec::new();
+            request.encode(&mut encoded);
+            assert_eq!(ControlRequest::decode(&encoded), Ok((request, encoded.len())));
+            for size in 0..encoded.len() {
+                assert!(matches!(ControlRequest::decode(&encoded[..size]), Err(DecodeError::Incomplete { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn data_packets_round_trip() {
+        for packet in [data_packet(0, 0), data_packet(1, 0), data_packet(3, 6), data_packet(2, 255), data_packet(255, 1)] {
+            let mut encoded = Vec::new();
+            packet.encode(&mut encoded).unwrap();
+            assert_eq!(DataPacket::decode(&encoded), Ok((packet, encoded.len())));
+            for size in 0..encoded.len() {
+                assert!(matches!(DataPacket::decode(&encoded[..size]), Err(DecodeError::Incomplete { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_data_packet_needs_the_rest_of_the_entry() {
+        let mut encoded = Vec::new();
+        data_packet(1, 6).encode(&mut encoded).unwrap();
+        assert_eq!(DataPacket::decode(&encoded[..12]), Err(DecodeError::Incomplete { needed: 16 }));
+        assert_eq!(DataPacket::decode(&encoded[..5]), Err(DecodeError::Incomplete { needed: 10 }));
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        // neither a TCP nor a UDP command byte
+        let unknown = 0x7F;
+        let input = [unknown; 16];
+        assert_eq!(AttachRequest::decode(&input), Err(DecodeError::UnexpectedCommand(unknown)));
+        assert_eq!(DetachRequest::decode(&input), Err(DecodeError::UnexpectedCommand(unknown)));
+        assert_eq!(Ping::decode(&input), Err(DecodeError::UnexpectedCommand(unknown)));
+        assert_eq!(Pong::decode(&input), Err(DecodeError::UnexpectedCommand(unknown)));
+        assert_eq!(ControlRequest::decode(&input), Err(DecodeError::UnexpectedCommand(unknown)));
+        assert_eq!(DataPacket::decode(&input), Err(DecodeError::UnexpectedCommand(unknown)));
+        assert_eq!(RumblePacket::decode(&input), Err(DecodeError::UnexpectedCommand(unknown)));
+    }
+
+    #[test]
+    fn messages_of_the_other_kind_are_rejected() {
+        let mut ping = Vec::new();
+        Ping.encode(&mut ping);
+        assert_eq!(Pong::decode(&ping), Err(DecodeError::UnexpectedCommand(ping[0])));
+
+        let mut detach = Vec::new();
+        DetachRequest { handle: 1 }.encode(&mut detach);
+        assert_eq!(AttachRequest::decode(&detach), Err(DecodeError::UnexpectedCommand(detach[0])));
+    }
+
+    #[test]
+    fn unknown_attach_reply_bytes_are_rejected() {
+        let mut encoded = Vec::new();
+        attach_reply().encode(&mut encoded);
+
+        let mut config = encoded.clone();
+        config[0] = 0x7F;
+        assert_eq!(AttachReply::decode(&config), Err(DecodeError::UnexpectedByte(0x7F)));
+
+        let mut user_data = encoded;
+        user_data[1] = 0x7F;
+        assert_eq!(AttachReply::decode(&user_data), Err(DecodeError::UnexpectedByte(0x7F)));
+    }
+
+    #[test]
+    fn oversized_reports_are_rejected() {
+        let mut out = Vec::new();
+        assert_eq!(DataPacket::write_entry(1, 0, 0, &[0; 256], &mut out), Err(EncodeError::ReportTooLarge(256)));
+        assert!(out.is_empty());
+        assert_eq!(DataPacket::entry_size(&[0; 256]), Err(EncodeError::ReportTooLarge(256)));
+
+        assert_eq!(data_packet(2, 256).encode(&mut out), Err(EncodeError::ReportTooLarge(256)));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn too_many_entries_are_rejected() {
+        let mut out = vec![1, 2, 3];
+        assert_eq!(data_packet(256, 1).encode(&mut out), Err(EncodeError::TooManyEntries(256)));
+        assert_eq!(out, [1, 2, 3]);
+    }
+}
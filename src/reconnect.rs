@@ -0,0 +1,102 @@
+use std::{fmt, time::Duration};
+
+/// How the control connection is retried after it could not be made or was lost.
+#[derive(Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of the delay it is randomly moved up or down by, between 0 and 1.
+    pub jitter: f64,
+    /// Failed attempts in a row before giving up, `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// Lost connections before giving up, `None` reconnects forever.
+    pub max_failures: Option<u32>
+}
+
+#[derive(Debug)]
+pub enum ReconnectError {
+    AttemptsExhausted(u32),
+    TooManyFailures(u32)
+}
+
+impl fmt::Display for ReconnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReconnectError::AttemptsExhausted(attempts) => write!(f, "unable to connect after {} attempts", attempts),
+            ReconnectError::TooManyFailures(failures) => write!(f, "connection lost {} times", failures)
+        }
+    }
+}
+
+impl std::error::Error for ReconnectError {}
+
+/// Keeps count of attempts and failures for a `ReconnectPolicy`.
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempts: u32,
+    failures: u32
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Backoff {
+        Backoff { policy, attempts: 0, failures: 0 }
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one.
+    pub fn failed_attempt(&mut self) -> Result<Duration, ReconnectError> {
+        self.attempts += 1;
+        if let Some(max) = self.policy.max_attempts {
+            if self.attempts >= max {
+                return Err(ReconnectError::AttemptsExhausted(self.attempts));
+            }
+        }
+
+        // doubles with every attempt, the shift is capped so it cannot overflow
+        let factor = 1u32 << (self.attempts - 1).min(16);
+        let delay = self.policy.initial_delay.saturating_mul(factor);
+
+        // spreads out clients that lost the console at the same time, before clamping so max_delay holds
+        let jitter = self.policy.jitter * (fastrand::f64() * 2.0 - 1.0);
+        Ok(delay.mul_f64(1.0 + jitter).min(self.policy.max_delay))
+    }
+
+    /// Records a successful connection, the next outage starts from the initial delay again.
+    pub fn connected(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Records the loss of an established connection.
+    pub fn connection_lost(&mut self) -> Result<(), ReconnectError> {
+        self.failures += 1;
+        match self.policy.max_failures {
+            Some(max) if self.failures >= max => Err(ReconnectError::TooManyFailures(self.failures)),
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Backoff, ReconnectPolicy};
+
+    fn policy(jitter: f64) -> ReconnectPolicy {
+        ReconnectPolicy { initial_delay: Duration::from_secs(1), max_delay: Duration::from_secs(10), jitter, max_attempts: None, max_failures: None }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new(policy(0.0));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.failed_attempt().unwrap().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn jitter_never_exceeds_the_maximum() {
+        let mut backoff = Backoff::new(policy(0.5));
+        for _ in 0..1000 {
+            assert!(backoff.failed_attempt().unwrap() <= Duration::from_secs(10));
+        }
+    }
+}
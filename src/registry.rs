@@ -0,0 +1,242 @@
+use std::{env, fs, io::ErrorKind, path::PathBuf};
+
+struct Device {
+    handle: i32,
+    uuid: String,
+    name: String
+}
+
+/// Remembers every gamepad seen, by UUID and name, so it keeps its handle across restarts and replugs.
+/// The order devices were first seen in is the order they are attached in, so they get the same slots again.
+/// Stored as one line per device: handle, UUID and name separated by tabs.
+pub struct DeviceRegistry {
+    /// `None` keeps the devices for this run only.
+    path: Option<PathBuf>,
+    devices: Vec<Device>,
+    /// What the file holds, as last read or written.
+    saved: String
+}
+
+/// `devices.txt` in the user's config directory, `None` when there is no home to find it in.
+pub fn default_path() -> Option<PathBuf> {
+    let config = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    config.map(|dir| dir.join("network-client").join("devices.txt"))
+}
+
+impl DeviceRegistry {
+    pub fn load(path: Option<PathBuf>) -> DeviceRegistry {
+        let mut registry = DeviceRegistry { path, devices: Vec::new(), saved: String::new() };
+        let text = match registry.path.as_ref().map(fs::read_to_string) {
+            Some(Ok(val)) => val,
+            Some(Err(e)) if e.kind() == ErrorKind::NotFound => return registry,
+            Some(Err(e)) => {
+                println!("[Registry] Unable to read the device registry, handles start over. Error: {}", e);
+                return registry;
+            },
+            None => return registry
+        };
+
+        let mut changed = false;
+        for line in text.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+            let mut fields = line.splitn(3, '\t');
+            let device = match (fields.next().map(str::parse::<i32>), fields.next(), fields.next()) {
+                (Some(Ok(handle)), Some(uuid), Some(name)) if handle > 0 => Device { handle, uuid: uuid.to_string(), name: name.to_string() },
+                _ => {
+                    println!("[Registry] Ignoring invalid line: {}", line);
+                    changed = true;
+                    continue;
+                }
+            };
+
+            // e.g. registries of two clients merged by hand
+            if let Some(other) = registry.devices.iter().find(|other| other.handle == device.handle) {
+                println!("[Registry] Handle collision: {} is listed for both {} and {}, giving the latter a new one", device.handle, other.name, device.name);
+                let handle = registry.new_handle();
+                registry.devices.push(Device { handle, ..device });
+                changed = true;
+                continue;
+            }
+            registry.devices.push(device);
+        }
+
+        registry.saved = text;
+        if changed {
+            registry.save();
+        }
+        registry
+    }
+
+    /// Handle of the first device with this UUID and name that is not `taken` by another gamepad plugged in,
+    /// identical gamepads share both. A gamepad never seen before is added with a new handle.
+    pub fn handle(&mut self, uuid: &str, name: &str, taken: &[i32]) -> i32 {
+        // kept on one line of the registry
+        let name = name.replace(['\t', '\n', '\r'], " ");
+        if let Some(device) = self.devices.iter().find(|device| device.uuid == uuid && device.name == name && !taken.contains(&device.handle)) {
+            return device.handle;
+        }
+
+        let handle = self.new_handle();
+        self.devices.push(Device { handle, uuid: uuid.to_string(), name });
+        self.save();
+        handle
+    }
+
+    /// Gives the device of `handle` a new handle, when the console already knows the old one from another client.
+    pub fn replace(&mut self, handle: i32) -> i32 {
+        let new_handle = self.new_handle();
+        if let Some(device) = self.devices.iter_mut().find(|device| device.handle == handle) {
+            device.handle = new_handle;
+            self.save();
+        }
+        new_handle
+    }
+
+    /// Where the device of `handle` comes in the preferred attach order, unknown handles come last.
+    pub fn position(&self, handle: i32) -> usize {
+        self.devices.iter().position(|device| device.handle == handle).unwrap_or(usize::MAX)
+    }
+
+    /// Random, so clients sharing a console are unlikely to pick the same handles.
+    fn new_handle(&self) -> i32 {
+        loop {
+            let handle = fastrand::i32(1..i32::MAX);
+            if !self.devices.iter().any(|device| device.handle == handle) {
+                return handle;
+            }
+        }
+    }
+
+    /// Writes the devices to the file, unless it already holds them.
+    fn save(&mut self) {
+        let path = match &self.path {
+            Some(val) => val,
+            None => return
+        };
+
+        let mut text = String::from("# Devices seen by the network client, in the order they are attached: handle, UUID and name\n");
+        for device in &self.devices {
+            text.push_str(&format!("{}\t{}\t{}\n", device.handle, device.uuid, device.name));
+        }
+        if text == self.saved {
+            return;
+        }
+
+        // written next to it first, a client killed halfway leaves the old registry intact
+        let temporary = path.with_extension("tmp");
+        let created = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+            _ => Ok(())
+        };
+        match created.and_then(|_| fs::write(&temporary, &text)).and_then(|_| fs::rename(&temporary, path)) {
+            Ok(_) => self.saved = text,
+            Err(e) => println!("[Registry] Unable to save the device registry. Error: {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::DeviceRegistry;
+
+    /// A registry file of its own for each test, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!("network-client-{}-{}.txt", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn handles_last_across_loads() {
+        let file = TempFile::new("reload");
+        let mut registry = DeviceRegistry::load(Some(file.0.clone()));
+        let first = registry.handle("uuid-1", "Pad", &[]);
+        let second = registry.handle("uuid-2", "Other\tPad", &[]);
+
+        let mut registry = DeviceRegistry::load(Some(file.0.clone()));
+        assert_eq!(registry.handle("uuid-1", "Pad", &[]), first);
+        assert_eq!(registry.handle("uuid-2", "Other Pad", &[]), second);
+        assert_eq!((registry.position(first), registry.position(second)), (0, 1));
+    }
+
+    #[test]
+    fn identical_gamepads_get_their_own_handles() {
+        let mut registry = DeviceRegistry::load(None);
+        let first = registry.handle("uuid", "Pad", &[]);
+        let second = registry.handle("uuid", "Pad", &[first]);
+        assert_ne!(first, second);
+        assert_eq!(registry.handle("uuid", "Pad", &[second]), first);
+    }
+
+    #[test]
+    fn known_devices_leave_the_file_alone() {
+        let file = TempFile::new("unchanged");
+        let handle = DeviceRegistry::load(Some(file.0.clone())).handle("uuid", "Pad", &[]);
+
+        // a file that is rewritten loses the comment
+        let text = format!("# kept\n{}\tuuid\tPad\n", handle);
+        fs::write(&file.0, &text).unwrap();
+        let mut registry = DeviceRegistry::load(Some(file.0.clone()));
+        assert_eq!(registry.handle("uuid", "Pad", &[]), handle);
+        assert_eq!(fs::read_to_string(&file.0).unwrap(), text);
+    }
+
+    #[test]
+    fn duplicate_handles_are_given_new_ones() {
+        let file = TempFile::new("duplicate");
+        fs::write(&file.0, "5\tuuid-1\tPad\n5\tuuid-2\tPad\n").unwrap();
+        let mut registry = DeviceRegistry::load(Some(file.0.clone()));
+        assert_eq!(registry.handle("uuid-1", "Pad", &[]), 5);
+        assert_ne!(registry.handle("uuid-2", "Pad", &[]), 5);
+        assert!(!fs::read_to_string(&file.0).unwrap().lines().any(|line| line == "5\tuuid-2\tPad"));
+    }
+
+    #[test]
+    fn replaced_handles_are_saved() {
+        let file = TempFile::new("replace");
+        let mut registry = DeviceRegistry::load(Some(file.0.clone()));
+        let old = registry.handle("uuid", "Pad", &[]);
+        let new = registry.replace(old);
+        assert_ne!(old, new);
+
+        let mut registry = DeviceRegistry::load(Some(file.0.clone()));
+        assert_eq!(registry.handle("uuid", "Pad", &[]), new);
+        assert_eq!(registry.position(old), usize::MAX);
+    }
+
+    #[test]
+    fn the_directory_is_created() {
+        let dir = std::env::temp_dir().join(format!("network-client-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("network-client").join("devices.txt");
+
+        let handle = DeviceRegistry::load(Some(path.clone())).handle("uuid", "Pad", &[]);
+        assert_eq!(DeviceRegistry::load(Some(path)).handle("uuid", "Pad", &[]), handle);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_written_without_a_file() {
+        let mut registry = DeviceRegistry::load(None);
+        registry.handle("uuid", "Pad", &[]);
+        assert!(!PathBuf::from("devices.txt").exists());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{commands::Frame, controller_manager::{ControllerManager, REPORT_SIZE}, protocol::{DataEntry, DataPacket}};
+
+/// Remembers which controllers the last frame carried, so they can be released on the console
+/// when the client stops sending input. Otherwise whatever was held stays held.
+#[derive(Default)]
+pub struct Safety {
+    last: Frame
+}
+
+impl Safety {
+    /// Called for every frame that was sent.
+    pub fn sent(&mut self, frame: &Frame) {
+        self.last.copy_from(frame);
+    }
+
+    /// A single controller with nothing pressed, for releasing it right before it is detached.
+    pub fn release_one(handle: i32, device_slot: i16, pad_slot: i8) -> Vec<u8> {
+        let packet = DataPacket { entries: vec![DataEntry { handle, device_slot, pad_slot, data: ControllerManager::neutral().to_vec() }] };
+        let mut datagram = Vec::new();
+        // one entry of a fixed size always fits
+        packet.encode(&mut datagram).unwrap();
+        datagram
+    }
+
+    /// The controllers of the last frame with nothing pressed, once. Empty when there is nothing to release.
+    pub fn release(&mut self) -> Vec<Vec<u8>> {
+        let released = self.last.datagrams()
+            .filter_map(|datagram| DataPacket::decode(datagram).ok())
+            .filter_map(|(mut packet, _)| {
+                for entry in &mut packet.entries {
+                    entry.data = if entry.data.len() == REPORT_SIZE { ControllerManager::neutral().to_vec() } else { vec![0; entry.data.len()] };
+                }
+
+                // the same sizes as the frame that was sent, so it fits again
+                let mut datagram = Vec::new();
+                packet.encode(&mut datagram).ok()?;
+                Some(datagram)
+            })
+            .collect();
+
+        self.last.clear();
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Safety;
+    use crate::{commands::{ControllerReport, Frame, WriteCommand}, controller_manager::{ControllerManager, REPORT_SIZE}, protocol::DataPacket};
+
+    /// A frame of `count` controllers with every button pressed, split into datagrams of two controllers.
+    fn frame(count: usize) -> Frame {
+        let reports = (0..count).map(|index| ControllerReport { handle: index as i32 + 1, device_slot: index as i16, pad_slot: 1, report: [0xFF; REPORT_SIZE] });
+        let mut frame = Frame::default();
+        WriteCommand::encode(reports, DataPacket::HEADER_SIZE + 2 * (8 + REPORT_SIZE), &mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn releases_every_controller_of_the_last_frame() {
+        let mut safety = Safety::default();
+        safety.sent(&frame(5));
+        safety.sent(&frame(3));
+
+        let released = safety.release();
+        assert_eq!(released.len(), 2);
+        let entries: Vec<_> = released.iter().flat_map(|datagram| DataPacket::decode(datagram).unwrap().0.entries).collect();
+        assert_eq!(entries.iter().map(|entry| (entry.handle, entry.device_slot, entry.pad_slot)).collect::<Vec<_>>(), [(1, 0, 1), (2, 1, 1), (3, 2, 1)]);
+        assert!(entries.iter().all(|entry| entry.data == ControllerManager::neutral()));
+    }
+
+    #[test]
+    fn releases_only_once() {
+        let mut safety = Safety::default();
+        safety.sent(&frame(2));
+        assert_eq!(safety.release().len(), 1);
+        assert!(safety.release().is_empty());
+    }
+
+    #[test]
+    fn nothing_to_release_before_sending() {
+        assert!(Safety::default().release().is_empty());
+        let mut safety = Safety::default();
+        safety.sent(&frame(0));
+        assert!(safety.release().is_empty());
+    }
+
+    #[test]
+    fn releases_a_single_controller() {
+        let (packet, _) = DataPacket::decode(&Safety::release_one(7, 2, 3)).unwrap();
+        assert_eq!(packet.entries.len(), 1);
+        let entry = &packet.entries[0];
+        assert_eq!((entry.handle, entry.device_slot, entry.pad_slot), (7, 2, 3));
+        assert_eq!(entry.data, ControllerManager::neutral());
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Instant;
+
+use crate::models::SendMode;
+
+/// Decides on which polls controller data is sent, following the `SendMode`.
+pub struct SendSchedule {
+    mode: SendMode,
+    last_sent: Instant,
+    /// Copies of the latest change still to be sent.
+    copies_left: u32
+}
+
+impl SendSchedule {
+    pub fn new(mode: SendMode, now: Instant) -> SendSchedule {
+        SendSchedule { mode, last_sent: now, copies_left: 0 }
+    }
+
+    /// Whether to send on the poll at `now`, `changed` when the input differs from the previous poll.
+    /// Returns which copy of the latest change it is, 0 for the change itself and for keepalives.
+    pub fn poll(&mut self, changed: bool, now: Instant) -> Option<u32> {
+        // copies go out on later polls, so they never exceed the polling rate
+        let copy = match self.mode {
+            SendMode::Fixed => Some(0),
+            SendMode::OnChange { copies, .. } if changed => {
+                self.copies_left = copies;
+                Some(0)
+            },
+            SendMode::OnChange { copies, spacing, .. } if self.copies_left > 0 && now >= self.last_sent + spacing => {
+                self.copies_left -= 1;
+                Some(copies - self.copies_left)
+            },
+            SendMode::OnChange { keepalive, .. } if now >= self.last_sent + keepalive => Some(0),
+            SendMode::OnChange { .. } => None
+        };
+
+        if copy.is_some() {
+            self.last_sent = now;
+        }
+        copy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::SendSchedule;
+    use crate::models::SendMode;
+
+    const KEEPALIVE: Duration = Duration::from_millis(1000);
+
+    fn ms(val: u64) -> Duration {
+        Duration::from_millis(val)
+    }
+
+    fn on_change(copies: u32, spacing: Duration) -> SendMode {
+        SendMode::OnChange { keepalive: KEEPALIVE, copies, spacing }
+    }
+
+    #[test]
+    fn fixed_sends_every_poll() {
+        let start = Instant::now();
+        let mut schedule = SendSchedule::new(SendMode::Fixed, start);
+        assert_eq!(schedule.poll(false, start), Some(0));
+        assert_eq!(schedule.poll(true, start + ms(1)), Some(0));
+        assert_eq!(schedule.poll(false, start + ms(2)), Some(0));
+    }
+
+    #[test]
+    fn on_change_sends_changes_only() {
+        let start = Instant::now();
+        let mut schedule = SendSchedule::new(on_change(0, ms(10)), start);
+        assert_eq!(schedule.poll(false, start + ms(1)), None);
+        assert_eq!(schedule.poll(true, start + ms(2)), Some(0));
+        assert_eq!(schedule.poll(false, start + ms(3)), None);
+        assert_eq!(schedule.poll(true, start + ms(4)), Some(0));
+    }
+
+    #[test]
+    fn keepalive_follows_the_last_send() {
+        let start = Instant::now();
+        let mut schedule = SendSchedule::new(on_change(0, ms(10)), start);
+        assert_eq!(schedule.poll(false, start + KEEPALIVE - ms(1)), None);
+        assert_eq!(schedule.poll(false, start + KEEPALIVE), Some(0));
+
+        // a change moves the next keepalive
+        let changed = start + KEEPALIVE + ms(500);
+        assert_eq!(schedule.poll(true, changed), Some(0));
+        assert_eq!(schedule.poll(false, start + KEEPALIVE * 2), None);
+        assert_eq!(schedule.poll(false, changed + KEEPALIVE - ms(1)), None);
+        assert_eq!(schedule.poll(false, changed + KEEPALIVE), Some(0));
+    }
+
+    #[test]
+    fn copies_follow_a_change_spaced_apart() {
+        let start = Instant::now();
+        let mut schedule = SendSchedule::new(on_change(2, ms(10)), start);
+        assert_eq!(schedule.poll(true, start), Some(0));
+        assert_eq!(schedule.poll(false, start + ms(5)), None);
+        assert_eq!(schedule.poll(false, start + ms(10)), Some(1));
+        assert_eq!(schedule.poll(false, start + ms(15)), None);
+        assert_eq!(schedule.poll(false, start + ms(20)), Some(2));
+        assert_eq!(schedule.poll(false, start + ms(30)), None);
+    }
+
+    #[test]
+    fn copies_are_spaced_from_when_the_last_one_went_out() {
+        let start = Instant::now();
+        let mut schedule = SendSchedule::new(on_change(2, ms(10)), start);
+        assert_eq!(schedule.poll(true, start), Some(0));
+        // a poll that came late
+        assert_eq!(schedule.poll(false, start + ms(25)), Some(1));
+        assert_eq!(schedule.poll(false, start + ms(30)), None);
+        assert_eq!(schedule.poll(false, start + ms(35)), Some(2));
+    }
+
+    #[test]
+    fn a_new_change_starts_the_copies_over() {
+        let start = Instant::now();
+        let mut schedule = SendSchedule::new(on_change(2, ms(10)), start);
+        assert_eq!(schedule.poll(true, start), Some(0));
+        assert_eq!(schedule.poll(false, start + ms(10)), Some(1));
+        assert_eq!(schedule.poll(true, start + ms(12)), Some(0));
+        assert_eq!(schedule.poll(false, start + ms(22)), Some(1));
+        assert_eq!(schedule.poll(false, start + ms(32)), Some(2));
+        assert_eq!(schedule.poll(false, start + ms(42)), None);
+    }
+
+    #[test]
+    fn keepalive_waits_for_the_copies() {
+        let start = Instant::now();
+        let mut schedule = SendSchedule::new(on_change(1, ms(10)), start);
+        assert_eq!(schedule.poll(true, start), Some(0));
+        assert_eq!(schedule.poll(false, start + ms(10)), Some(1));
+        assert_eq!(schedule.poll(false, start + KEEPALIVE), None);
+        assert_eq!(schedule.poll(false, start + ms(10) + KEEPALIVE), Some(0));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{sync::{Condvar, Mutex}, time::{Duration, Instant}};
+
+use crate::models::ApplicationState;
+
+type Waker = Box<dyn Fn(ApplicationState) + Send>;
+
+/// `ApplicationState` shared by every thread.
+/// Threads waiting on it are woken as soon as it changes.
+pub struct SharedState {
+    state: Mutex<ApplicationState>,
+    changed: Condvar,
+    wakers: Mutex<Vec<Waker>>
+}
+
+impl SharedState {
+    pub fn new(state: ApplicationState) -> SharedState {
+        SharedState {
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            wakers: Mutex::new(Vec::new())
+        }
+    }
+
+    pub fn get(&self) -> ApplicationState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Changes the state and wakes everyone. `Exiting` is final and never replaced, `ShuttingDown` only by `Exiting`.
+    pub fn set(&self, new: ApplicationState) {
+        self.update(|current| match current {
+            ApplicationState::Exiting => None,
+            ApplicationState::ShuttingDown if !new.is_exiting() => None,
+            _ => Some(new)
+        });
+    }
+
+    /// Changes the state only while it still is `current`, returns whether it did.
+    pub fn compare_and_set(&self, current: ApplicationState, new: ApplicationState) -> bool {
+        self.update(|state| if state == current { Some(new) } else { None })
+    }
+
+    fn update(&self, change: impl FnOnce(ApplicationState) -> Option<ApplicationState>) -> bool {
+        let new = {
+            let mut state = self.state.lock().unwrap();
+            match change(*state) {
+                Some(new) if new != *state => {
+                    *state = new;
+                    new
+                },
+                Some(_) => return true,
+                None => return false
+            }
+        };
+
+        self.changed.notify_all();
+        for waker in self.wakers.lock().unwrap().iter() {
+            waker(new);
+        }
+        true
+    }
+
+    /// Blocks while `condition` holds for the current state, at most for `timeout` when given.
+    /// Returns the state it stopped waiting on.
+    pub fn wait_while(&self, condition: impl Fn(ApplicationState) -> bool, timeout: Option<Duration>) -> ApplicationState {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        while condition(*state) {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap().0
+                },
+                None => self.changed.wait(state).unwrap()
+            };
+        }
+        *state
+    }
+
+    /// Runs `waker` on every change, for threads blocked on something other than this state.
+    pub fn on_change(&self, waker: impl Fn(ApplicationState) + Send + 'static) {
+        self.wakers.lock().unwrap().push(Box::new(waker));
+    }
+}
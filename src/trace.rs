@@ -0,0 +1,72 @@
+use std::{cell::RefCell, fmt, net::SocketAddr, time::Instant};
+
+use crate::{capture::Capture, models::Direction};
+
+/// Bytes shown per line of a hex dump.
+const LINE_WIDTH: usize = 16;
+
+#[derive(Clone, Copy)]
+pub enum Channel {
+    Control,
+    Data,
+    Rumble
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Channel::Control => write!(f, "TCP"),
+            Channel::Data => write!(f, "UDP data"),
+            Channel::Rumble => write!(f, "UDP rumble")
+        }
+    }
+}
+
+/// Taps every exchange with the Wii U, logging what it means with the raw bytes and capturing it to a file.
+pub struct Tracer {
+    /// `None` while tracing is off.
+    started: Option<Instant>,
+    capture: RefCell<Option<Capture>>
+}
+
+impl Tracer {
+    pub fn new(enabled: bool, capture: Option<Capture>) -> Tracer {
+        Tracer {
+            started: if enabled { Some(Instant::now()) } else { None },
+            capture: RefCell::new(capture)
+        }
+    }
+
+    /// Addresses the capture shows for `channel` from now on.
+    pub fn set_endpoints(&self, channel: Channel, local: SocketAddr, remote: SocketAddr) {
+        if let Some(capture) = self.capture.borrow_mut().as_mut() {
+            capture.set_endpoints(channel, local, remote);
+        }
+    }
+
+    /// `describe` only runs when tracing is on.
+    pub fn record(&self, channel: Channel, direction: Direction, bytes: &[u8], describe: impl FnOnce() -> String) {
+        let mut capture = self.capture.borrow_mut();
+        if let Some(Err(e)) = capture.as_mut().map(|capture| capture.write(channel, direction, bytes)) {
+            println!("[Capture] Unable to write the capture, stopping it. Error: {}", e);
+            *capture = None;
+        }
+
+        let started = match self.started {
+            Some(val) => val,
+            None => return
+        };
+
+        let arrow = match direction {
+            Direction::Outgoing => "->",
+            Direction::Incoming => "<-"
+        };
+        println!("[Trace] {:>11.6} {} {} {} ({} bytes)", started.elapsed().as_secs_f64(), channel, arrow, describe(), bytes.len());
+
+        for (line, chunk) in bytes.chunks(LINE_WIDTH).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|val| format!("{:02x}", val)).collect();
+            let text: String = chunk.iter().map(|&val| if val.is_ascii_graphic() { val as char } else { '.' }).collect();
+            println!("        {:04x}  {:<width$}  {}", line * LINE_WIDTH, hex.join(" "), text, width = LINE_WIDTH * 3 - 1);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{cell::UnsafeCell, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+
+/// Set on the middle index while it holds a value the reader has not taken yet.
+const FRESH: usize = 0b100;
+const INDEX: usize = 0b011;
+
+/// Hands the latest value from one writer to one reader without locks or waiting.
+/// Each side owns one buffer and they swap through the third, so a value is never copied
+/// and an unread one is simply replaced.
+struct Shared<T> {
+    buffers: [UnsafeCell<T>; 3],
+    middle: AtomicUsize
+}
+
+// SAFETY: each buffer is only ever reachable from one side at a time, the atomic swap hands it over.
+// Values are moved between threads that way, hence `T: Send`.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+pub struct Writer<T> {
+    shared: Arc<Shared<T>>,
+    back: usize
+}
+
+pub struct Reader<T> {
+    shared: Arc<Shared<T>>,
+    front: usize
+}
+
+pub fn triple_buffer<T: Default>() -> (Writer<T>, Reader<T>) {
+    let shared = Arc::new(Shared {
+        buffers: [UnsafeCell::new(T::default()), UnsafeCell::new(T::default()), UnsafeCell::new(T::default())],
+        middle: AtomicUsize::new(1)
+    });
+
+    (Writer { shared: shared.clone(), back: 0 }, Reader { shared, front: 2 })
+}
+
+impl<T> Writer<T> {
+    /// The buffer to prepare the next value in, it still holds some older value.
+    pub fn back(&mut self) -> &mut T {
+        // SAFETY: `back` is never the middle or the reader's index, only this writer reaches it until `publish`,
+        // which takes `&mut self` and so cannot run while the reference lives
+        unsafe { &mut *self.shared.buffers[self.back].get() }
+    }
+
+    /// Makes the back buffer the latest value. Returns false when it replaced one that was never read.
+    pub fn publish(&mut self) -> bool {
+        let previous = self.shared.middle.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = previous & INDEX;
+        previous & FRESH == 0
+    }
+}
+
+impl<T> Reader<T> {
+    /// The latest value, if one was published since the last call.
+    pub fn take(&mut self) -> Option<&mut T> {
+        if self.shared.middle.load(Ordering::Relaxed) & FRESH == 0 {
+            return None;
+        }
+
+        let previous = self.shared.middle.swap(self.front, Ordering::AcqRel);
+        self.front = previous & INDEX;
+        // SAFETY: the swap took `front` out of the middle, the writer cannot reach it until the next `take`,
+        // which needs `&mut self` and so ends this borrow first. AcqRel pairs with the Release of `publish`,
+        // everything the writer did to the buffer is visible.
+        Some(unsafe { &mut *self.shared.buffers[self.front].get() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::triple_buffer;
+
+    #[test]
+    fn nothing_to_take_before_publishing() {
+        let (_writer, mut reader) = triple_buffer::<u32>();
+        assert_eq!(reader.take(), None);
+    }
+
+    #[test]
+    fn takes_each_published_value_once() {
+        let (mut writer, mut reader) = triple_buffer();
+        *writer.back() = 1;
+        assert!(writer.publish());
+        assert_eq!(reader.take().copied(), Some(1));
+        assert_eq!(reader.take(), None);
+
+        *writer.back() = 2;
+        assert!(writer.publish());
+        assert_eq!(reader.take().copied(), Some(2));
+    }
+
+    #[test]
+    fn unread_values_are_replaced_by_newer_ones() {
+        let (mut writer, mut reader) = triple_buffer();
+        for value in 1..=5 {
+            *writer.back() = value;
+            // only the first one finds the middle empty
+            assert_eq!(writer.publish(), value == 1);
+        }
+
+        assert_eq!(reader.take().copied(), Some(5));
+        assert_eq!(reader.take(), None);
+    }
+
+    #[test]
+    fn reader_keeps_its_value_while_the_writer_goes_on() {
+        let (mut writer, mut reader) = triple_buffer();
+        *writer.back() = 1;
+        writer.publish();
+        let taken = reader.take().unwrap();
+
+        for value in 2..10 {
+            *writer.back() = value;
+            writer.publish();
+        }
+        assert_eq!(*taken, 1);
+        assert_eq!(reader.take().copied(), Some(9));
+    }
+
+    #[test]
+    fn frames_are_never_torn_or_stale_across_threads() {
+        const FRAMES: u64 = 200_000;
+        let (mut writer, mut reader) = triple_buffer::<[u64; 32]>();
+
+        let writing = thread::spawn(move || {
+            for frame in 1..=FRAMES {
+                *writer.back() = [frame; 32];
+                writer.publish();
+            }
+        });
+
+        let mut last = 0;
+        while last < FRAMES {
+            if let Some(frame) = reader.take() {
+                assert!(frame.iter().all(|&val| val == frame[0]), "torn frame: {:?}", frame);
+                assert!(frame[0] > last, "frame {} taken after {}", frame[0], last);
+                last = frame[0];
+            }
+        }
+
+        writing.join().unwrap();
+        assert_eq!(reader.take(), None);
+    }
+}
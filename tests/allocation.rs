@@ -0,0 +1,79 @@
+mod common;
+
+use std::{alloc::{GlobalAlloc, Layout, System}, net::{Ipv4Addr, UdpSocket}, sync::atomic::{AtomicBool, AtomicU64, Ordering}, thread, time::{Duration, Instant}};
+
+use network_client::models::{ApplicationState, ProtocolVersion};
+
+/// Counts the allocations of every thread while asked to.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static COUNTING: AtomicBool = AtomicBool::new(false);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+fn count() {
+    if COUNTING.load(Ordering::Relaxed) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Shorter than the ping interval, so the window fits between two pings.
+const WINDOW: Duration = Duration::from_millis(500);
+
+fn pongs(client: &common::Client) -> usize {
+    client.latency.lock().unwrap().summary().map(|summary| summary.samples).unwrap_or(0)
+}
+
+#[test]
+fn sending_input_does_not_allocate() {
+    // the emulator allocates for every packet it takes apart, the data goes to a socket nobody reads instead
+    let emulator = common::emulator(ProtocolVersion::Version3, None);
+    let data = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let mut client = common::client(common::network_config(emulator.tcp_port(), data.local_addr().unwrap().port(), Duration::from_secs(1), None));
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+
+    // every buffer of the triple buffer passes through the writer and grows to size
+    for frame in 0..100 {
+        client.send_input(frame);
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // right after a pong, the next ping is a whole interval away
+    let before = pongs(&client);
+    assert!(common::wait_for(Duration::from_secs(5), || pongs(&client) > before));
+
+    let started = Instant::now();
+    let mut frame = 100;
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    COUNTING.store(true, Ordering::Relaxed);
+    while started.elapsed() < WINDOW {
+        client.send_input(frame);
+        frame += 1;
+        thread::sleep(Duration::from_millis(1));
+    }
+    COUNTING.store(false, Ordering::Relaxed);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+    assert_eq!(allocations, 0, "{} allocations while sending {} frames", allocations, frame - 100);
+    data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(data.recv(&mut [0; 1500]).is_ok());
+    client.exit().unwrap();
+}
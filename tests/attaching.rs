@@ -0,0 +1,188 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use network_client::{attach::{AttachEvent, AttachQueue, AttachStatus}, emulator::{self, Emulator, EmulatorConfig, Unresponsive}, models::{ApplicationState, GENERIC_PID, GENERIC_VID, ProtocolVersion}, network::NetworkHandle, protocol::{ConfigStatus, UserDataStatus}};
+
+const IDS: (i16, i16) = (0x057E, 0x0306);
+
+/// An emulator handing out `pad_slot` and device slots from `device_slot` on, on `tcp_port` once the previous one let go of it.
+fn emulator_on(tcp_port: u16, device_slot: i16, pad_slot: i8, config_found: bool, user_data_okay: bool) -> Emulator {
+    let start = || emulator::start(EmulatorConfig {
+        version: ProtocolVersion::Version3,
+        config_found,
+        user_data_okay,
+        device_slot,
+        pad_slot,
+        tcp_port,
+        udp_port: 0,
+        rumble_port: 0,
+        unresponsive: None
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match start() {
+            Ok(emulator) => return emulator,
+            Err(e) if Instant::now() < deadline => println!("Waiting for the port: {}", e),
+            Err(e) => panic!("unable to start the emulator: {}", e)
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn connected(emulator: &Emulator) -> common::Client {
+    let client = common::client(common::network_config(emulator.tcp_port(), emulator.udp_port(), Duration::from_secs(1), None));
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+    client
+}
+
+/// Polls the queue until something happens to a request.
+fn next_events(queue: &mut AttachQueue<usize>, network: &NetworkHandle) -> Vec<AttachEvent<usize>> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        let events = queue.poll(network, Instant::now());
+        if !events.is_empty() {
+            return events;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("no attach events");
+}
+
+fn failed(events: Vec<AttachEvent<usize>>) -> (String, Duration) {
+    match events.as_slice() {
+        [AttachEvent::Failed { id: 1, reason, retry_in }] => (reason.clone(), *retry_in),
+        [AttachEvent::Attached { .. }] => panic!("attached"),
+        _ => panic!("{} events instead of a failure", events.len())
+    }
+}
+
+fn attached(events: Vec<AttachEvent<usize>>) -> (i16, i8, ConfigStatus, UserDataStatus) {
+    match events.as_slice() {
+        [AttachEvent::Attached { id: 1, handle: 1, response }] => (response.device_slot, response.pad_slot, response.config, response.user_data),
+        [AttachEvent::Failed { reason, .. }] => panic!("failed: {}", reason),
+        _ => panic!("{} events instead of an attach", events.len())
+    }
+}
+
+fn status(queue: &AttachQueue<usize>) -> Option<AttachStatus> {
+    queue.statuses().next().map(|status| status.status)
+}
+
+#[test]
+fn failed_attaches_are_detached_and_retried_later_and_later() {
+    let emulator = emulator_on(0, 0, -1, true, true);
+    let client = connected(&emulator);
+    let mut queue = AttachQueue::default();
+
+    assert!(queue.request(1, 1, "Pad", IDS, &client.network).is_none());
+    assert!(matches!(status(&queue), Some(AttachStatus::Pending)));
+    assert_eq!(queue.pending_handles().collect::<Vec<_>>(), vec![1]);
+
+    let (reason, first_retry) = failed(next_events(&mut queue, &client.network));
+    assert!(reason.contains("invalid slots"), "{}", reason);
+    assert!(first_retry >= Duration::from_millis(900) && first_retry <= Duration::from_millis(1100), "{:?}", first_retry);
+    assert!(matches!(status(&queue), Some(AttachStatus::Failed { .. })));
+    assert_eq!(queue.pending_handles().count(), 0);
+    // the console attached it on the invalid slots, it is detached again
+    assert!(common::wait_for(Duration::from_secs(5), || emulator.devices().is_empty()));
+
+    // nothing happens before the retry is due
+    let failed_at = Instant::now();
+    assert!(queue.poll(&client.network, failed_at).is_empty());
+    assert!(matches!(queue.poll(&client.network, failed_at + first_retry).as_slice(), [AttachEvent::Retrying { id: 1 }]));
+    assert!(matches!(status(&queue), Some(AttachStatus::Pending)));
+
+    let (_, second_retry) = failed(next_events(&mut queue, &client.network));
+    assert!(second_retry >= Duration::from_millis(1800) && second_retry <= Duration::from_millis(2200), "{:?}", second_retry);
+    client.exit().unwrap();
+}
+
+#[test]
+fn devices_without_a_config_are_attached_with_the_generic_ids() {
+    let emulator = emulator_on(0, 0, 0, false, true);
+    let client = connected(&emulator);
+    let mut queue = AttachQueue::default();
+
+    queue.request(1, 1, "Pad", IDS, &client.network);
+    let (_, _, config, _) = attached(next_events(&mut queue, &client.network));
+    assert_eq!(config, ConfigStatus::NotFound);
+    assert_eq!(queue.statuses().count(), 0);
+
+    // the first attach is detached before the generic one
+    let devices = emulator.devices();
+    assert_eq!(devices.len(), 1);
+    assert_eq!((devices[0].vid, devices[0].pid), (GENERIC_VID, GENERIC_PID));
+    client.exit().unwrap();
+}
+
+#[test]
+fn bad_user_data_still_attaches() {
+    let emulator = emulator_on(0, 0, 0, true, false);
+    let client = connected(&emulator);
+    let mut queue = AttachQueue::default();
+
+    queue.request(1, 1, "Pad", IDS, &client.network);
+    let (_, _, _, user_data) = attached(next_events(&mut queue, &client.network));
+    assert_eq!(user_data, UserDataStatus::Bad);
+    assert_eq!(emulator.devices().len(), 1);
+    client.exit().unwrap();
+}
+
+#[test]
+fn requests_lost_with_the_connection_are_retried_once_reconnected() {
+    // the reply never comes, the connection is given up on instead
+    let first = common::emulator(ProtocolVersion::Version3, Some(Unresponsive::Requests));
+    let port = first.tcp_port();
+    let client = common::client(common::network_config(port, first.udp_port(), Duration::from_millis(300), None));
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+    let mut queue = AttachQueue::default();
+
+    queue.request(1, 1, "Pad", IDS, &client.network);
+    let (reason, retry_in) = failed(next_events(&mut queue, &client.network));
+    assert!(reason.contains("connection lost"), "{}", reason);
+
+    drop(first);
+    let second = emulator_on(port, 4, 2, true, true);
+    assert!(common::wait_for(Duration::from_secs(5), || second.client().is_some()));
+    // long before the retry is due
+    queue.retry_now();
+    let events = queue.poll(&client.network, Instant::now());
+    assert!(matches!(events.as_slice(), [AttachEvent::Retrying { id: 1 }]), "retry was due in {:?}", retry_in);
+
+    assert_eq!(attached(next_events(&mut queue, &client.network)).0, 4);
+    assert_eq!(second.devices().len(), 1);
+    client.exit().unwrap();
+}
+
+#[test]
+fn slots_of_the_old_connection_are_never_reported_after_a_failed_reattach() {
+    let first = emulator_on(0, 1, 1, true, true);
+    let port = first.tcp_port();
+    let client = connected(&first);
+    let mut queue = AttachQueue::default();
+
+    queue.request(1, 1, "Pad", IDS, &client.network);
+    assert_eq!(attached(next_events(&mut queue, &client.network)).0, 1);
+
+    // as the controller thread does, everything is attached again after reconnecting
+    drop(first);
+    let second = emulator_on(port, 0, -1, true, true);
+    assert!(client.reconnections.recv_timeout(Duration::from_secs(5)).is_ok());
+    queue.request(1, 1, "Pad", IDS, &client.network);
+    failed(next_events(&mut queue, &client.network));
+    assert!(common::wait_for(Duration::from_secs(5), || second.devices().is_empty()));
+    assert!(matches!(status(&queue), Some(AttachStatus::Failed { .. })));
+
+    drop(second);
+    let third = emulator_on(port, 5, 3, true, true);
+    assert!(client.reconnections.recv_timeout(Duration::from_secs(5)).is_ok());
+    queue.retry_now();
+    assert!(matches!(queue.poll(&client.network, Instant::now()).as_slice(), [AttachEvent::Retrying { id: 1 }]));
+
+    let (device_slot, pad_slot, _, _) = attached(next_events(&mut queue, &client.network));
+    assert_eq!((device_slot, pad_slot), (5, 3));
+    assert_eq!(third.devices()[0].device_slot, 5);
+    client.exit().unwrap();
+}
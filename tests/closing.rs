@@ -0,0 +1,56 @@
+mod common;
+
+use std::{net::{Ipv4Addr, TcpListener}, time::{Duration, Instant}};
+
+use network_client::{emulator::{self, EmulatorConfig, Unresponsive}, models::{ApplicationState, AttachData, AttachOutcome, DetachData, ProtocolVersion, TcpMessage}};
+
+#[test]
+fn detaches_sent_right_before_exiting_arrive() {
+    let emulator = common::emulator(ProtocolVersion::Version3, None);
+    let client = common::client(common::network_config(emulator.tcp_port(), emulator.udp_port(), Duration::from_secs(1), None));
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+
+    let (response, outcome) = flume::bounded(1);
+    client.network.send_tcp(TcpMessage::Attach(AttachData { handle: 1, vid: 0x057E, pid: 0x0306, response })).unwrap();
+    assert!(matches!(outcome.recv_timeout(Duration::from_secs(5)), Ok(AttachOutcome::Attached(_))));
+    assert_eq!(emulator.devices().len(), 1);
+
+    // the detach is still in the channel when the network loop is told to exit
+    client.network.send_tcp(TcpMessage::Detach(DetachData { handle: 1, release: None })).unwrap();
+    client.exit().unwrap();
+
+    assert!(common::wait_for(Duration::from_secs(5), || emulator.client().is_none()));
+    assert!(emulator.devices().is_empty());
+}
+
+#[test]
+fn closing_waits_up_to_the_control_timeout_for_a_stuck_server() {
+    const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+    let client = common::client(common::network_config(port, 0, CONTROL_TIMEOUT, None));
+
+    // far more than the socket buffers take, waiting in the channel until there is a server to send them to
+    for handle in 0..1_000_000 {
+        client.network.send_tcp(TcpMessage::Detach(DetachData { handle, release: None })).unwrap();
+    }
+    // completes the handshake, then never reads what is sent
+    let _emulator = emulator::start(EmulatorConfig {
+        version: ProtocolVersion::Version3,
+        config_found: true,
+        user_data_okay: true,
+        device_slot: 0,
+        pad_slot: 0,
+        tcp_port: port,
+        udp_port: 0,
+        rumble_port: 0,
+        unresponsive: Some(Unresponsive::Requests)
+    }).unwrap();
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+
+    let started = Instant::now();
+    client.exit().unwrap();
+
+    assert!(started.elapsed() >= CONTROL_TIMEOUT - Duration::from_millis(100), "gave up after only {:?}", started.elapsed());
+    // the client binary waits this long for the network thread, on top of its shutdown timeout
+    assert!(started.elapsed() < CONTROL_TIMEOUT + Duration::from_secs(2), "still closing after {:?}", started.elapsed());
+}
@@ -0,0 +1,107 @@
+#![allow(dead_code)]
+
+use std::{net::{Ipv4Addr, SocketAddr}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use flume::Receiver;
+use network_client::{commands::{ControllerReport, WriteCommand}, controller_manager::REPORT_SIZE, emulator::{self, Emulator, EmulatorConfig, Unresponsive}, latency::LatencyStats, models::{ApplicationState, NetworkConfig, ProtocolVersion, Rumble}, network::{self, FrameSender, NetworkHandle, NetworkThread}, reconnect::{ReconnectError, ReconnectPolicy}, state::SharedState};
+
+/// An emulator offering `version` on free loopback ports.
+pub fn emulator(version: ProtocolVersion, unresponsive: Option<Unresponsive>) -> Emulator {
+    emulator::start(EmulatorConfig {
+        version,
+        config_found: true,
+        user_data_okay: true,
+        device_slot: 0,
+        pad_slot: 0,
+        tcp_port: 0,
+        udp_port: 0,
+        rumble_port: 0,
+        unresponsive
+    }).unwrap()
+}
+
+pub fn control_addr(emulator: &Emulator) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), emulator.tcp_port())
+}
+
+/// Waits up to `timeout` for `condition` to hold, returning whether it did.
+pub fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    condition()
+}
+
+/// Client settings for a server on loopback `tcp_port`, giving up after `max_attempts` failed connection attempts.
+pub fn network_config(tcp_port: u16, udp_port: u16, control_timeout: Duration, max_attempts: Option<u32>) -> NetworkConfig {
+    NetworkConfig {
+        host: Ipv4Addr::LOCALHOST.to_string(),
+        tcp_port,
+        udp_port,
+        data_port: 0,
+        rumble_port: 0,
+        bind_address: None,
+        interface: None,
+        reconnect: ReconnectPolicy {
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+            max_attempts,
+            max_failures: None
+        },
+        control_timeout,
+        ping_timeout: Duration::from_secs(5),
+        max_missed_pongs: 3,
+        mtu: 1500,
+        impairment: None,
+        trace: false,
+        capture: None
+    }
+}
+
+/// A running client, the receivers are kept so the network loop can hand out reconnections and rumble.
+pub struct Client {
+    pub network: NetworkHandle,
+    pub frames: FrameSender,
+    pub thread: NetworkThread,
+    pub state: Arc<SharedState>,
+    pub reconnections: Receiver<()>,
+    pub rumble: Receiver<Rumble>,
+    pub latency: Arc<Mutex<LatencyStats>>
+}
+
+pub fn client(config: NetworkConfig) -> Client {
+    let (reconnection_sender, reconnections) = flume::unbounded();
+    let (rumble_sender, rumble) = flume::unbounded();
+    let state = Arc::new(SharedState::new(ApplicationState::Disconnected));
+    let latency = Arc::new(Mutex::new(LatencyStats::default()));
+    let (network, frames, thread) = network::start(config, reconnection_sender, rumble_sender, state.clone(), latency.clone()).unwrap();
+    Client { network, frames, thread, state, reconnections, rumble, latency }
+}
+
+impl Client {
+    /// Encodes and sends the input of four controllers, as the input loop does every tick.
+    pub fn send_input(&mut self, tick: u64) {
+        let reports = (0..4).map(|index| ControllerReport {
+            handle: index + 1,
+            device_slot: index as i16,
+            pad_slot: 0,
+            report: [tick as u8; REPORT_SIZE]
+        });
+        let max_datagram = self.frames.max_datagram();
+        self.frames.send(|frame| {
+            frame.change = tick;
+            WriteCommand::encode(reports, max_datagram, frame)
+        }).unwrap();
+    }
+
+    /// Asks the network loop to leave and waits for it.
+    pub fn exit(self) -> Result<(), ReconnectError> {
+        self.state.set(ApplicationState::Exiting);
+        self.thread.join().unwrap()
+    }
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use std::{io::Write, net::{Ipv4Addr, SocketAddr, TcpListener}, thread, time::{Duration, Instant}};
+
+use network_client::{emulator::Unresponsive, models::ProtocolVersion, network};
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+#[test]
+fn probe_finds_the_offered_version() {
+    for version in [ProtocolVersion::Version1, ProtocolVersion::Version2, ProtocolVersion::Version3] {
+        let emulator = common::emulator(version, None);
+        assert_eq!(network::probe(common::control_addr(&emulator), TIMEOUT), Some(version));
+    }
+}
+
+#[test]
+fn probe_leaves_without_taking_the_console() {
+    let emulator = common::emulator(ProtocolVersion::Version3, None);
+    assert!(network::probe(common::control_addr(&emulator), TIMEOUT).is_some());
+
+    // the emulator serves one client at a time, it is free again right away
+    assert!(network::probe(common::control_addr(&emulator), TIMEOUT).is_some());
+    assert_eq!(emulator.client(), None);
+}
+
+#[test]
+fn probe_rejects_servers_that_stay_silent() {
+    let emulator = common::emulator(ProtocolVersion::Version3, Some(Unresponsive::Handshake));
+    let started = Instant::now();
+    assert_eq!(network::probe(common::control_addr(&emulator), TIMEOUT), None);
+    assert!(started.elapsed() < TIMEOUT * 2);
+}
+
+#[test]
+fn probe_rejects_unknown_versions() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listener.local_addr().unwrap().port());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&[0x15]).unwrap();
+    });
+
+    assert_eq!(network::probe(addr, TIMEOUT), None);
+    server.join().unwrap();
+}
+
+#[test]
+fn probe_rejects_closed_ports() {
+    let addr = {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listener.local_addr().unwrap().port())
+    };
+    assert_eq!(network::probe(addr, TIMEOUT), None);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use std::{thread, time::{Duration, Instant}};
+
+use network_client::{emulator::Emulator, impairment::ImpairmentConfig, models::{ApplicationState, ProtocolVersion}};
+
+/// A client connected to a fresh emulator, its controller data passing through `impairment`.
+fn impaired_client(impairment: ImpairmentConfig) -> (Emulator, common::Client) {
+    let emulator = common::emulator(ProtocolVersion::Version3, None);
+    let mut config = common::network_config(emulator.tcp_port(), emulator.udp_port(), Duration::from_secs(1), None);
+    config.impairment = Some(impairment);
+
+    let client = common::client(config);
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+    (emulator, client)
+}
+
+/// Sends `frames` frames, each given time to be picked up by the network loop before the next replaces it.
+fn send_frames(client: &mut common::Client, frames: u64) {
+    for frame in 0..frames {
+        client.send_input(frame);
+        thread::sleep(Duration::from_millis(2));
+    }
+}
+
+#[test]
+fn dropping_everything_delivers_nothing() {
+    let (emulator, mut client) = impaired_client(ImpairmentConfig { drop: 1.0, ..Default::default() });
+    send_frames(&mut client, 50);
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(emulator.packets(), 0);
+    client.exit().unwrap();
+}
+
+#[test]
+fn dropping_half_delivers_about_half() {
+    const FRAMES: u64 = 300;
+    let (emulator, mut client) = impaired_client(ImpairmentConfig { drop: 0.5, ..Default::default() });
+    send_frames(&mut client, FRAMES);
+
+    thread::sleep(Duration::from_millis(200));
+    // far outside these bounds is next to impossible with 300 fair coin flips
+    let packets = emulator.packets();
+    assert!((FRAMES / 4..FRAMES * 3 / 4).contains(&packets), "{} of {} frames arrived", packets, FRAMES);
+    client.exit().unwrap();
+}
+
+#[test]
+fn delayed_data_arrives_late() {
+    const DELAY: Duration = Duration::from_millis(300);
+    let (emulator, mut client) = impaired_client(ImpairmentConfig { delay: DELAY, ..Default::default() });
+
+    let sent = Instant::now();
+    client.send_input(1);
+    assert!(common::wait_for(Duration::from_secs(5), || emulator.packets() > 0));
+    assert!(sent.elapsed() >= DELAY, "arrived after {:?}", sent.elapsed());
+    client.exit().unwrap();
+}
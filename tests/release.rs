@@ -0,0 +1,41 @@
+mod common;
+
+use std::time::Duration;
+
+use network_client::{controller_manager::{ControllerManager, REPORT_SIZE}, impairment::ImpairmentConfig, models::{ApplicationState, AttachData, AttachOutcome, DetachData, ProtocolVersion, TcpMessage}};
+
+#[test]
+fn controllers_are_released_when_the_connection_drops() {
+    let emulator = common::emulator(ProtocolVersion::Version3, None);
+    let mut config = common::network_config(emulator.tcp_port(), emulator.udp_port(), Duration::from_secs(1), None);
+    config.impairment = Some(ImpairmentConfig { disconnect: Some(Duration::from_secs(1)), ..Default::default() });
+    let mut client = common::client(config);
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+
+    let (response, outcome) = flume::bounded(1);
+    client.network.send_tcp(TcpMessage::Attach(AttachData { handle: 1, vid: 0x057E, pid: 0x0306, response })).unwrap();
+    assert!(matches!(outcome.recv_timeout(Duration::from_secs(5)), Ok(AttachOutcome::Attached(_))));
+
+    // the emulator forgets the devices once the client is back, but not their data
+    let last_data = || emulator.last_data(1).unwrap_or_default();
+    client.send_input(7);
+    assert!(common::wait_for(Duration::from_secs(1), || last_data() == [7; REPORT_SIZE]));
+
+    assert!(common::wait_for(Duration::from_secs(3), || last_data() == ControllerManager::neutral()), "still holding {:02x?}", last_data());
+    client.exit().unwrap();
+}
+
+#[test]
+fn detached_controllers_are_released_first() {
+    let emulator = common::emulator(ProtocolVersion::Version3, None);
+    let mut client = common::client(common::network_config(emulator.tcp_port(), emulator.udp_port(), Duration::from_secs(1), None));
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+
+    client.send_input(7);
+    assert!(common::wait_for(Duration::from_secs(1), || emulator.last_data(1) == Some(vec![7; REPORT_SIZE])));
+
+    // released by the network loop itself, no frame is involved that newer input could replace
+    client.network.send_tcp(TcpMessage::Detach(DetachData { handle: 1, release: Some((0, 0)) })).unwrap();
+    assert!(common::wait_for(Duration::from_secs(1), || emulator.last_data(1) == Some(ControllerManager::neutral().to_vec())), "last data {:02x?}", emulator.last_data(1));
+    client.exit().unwrap();
+}
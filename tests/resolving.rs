@@ -0,0 +1,45 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use network_client::{models::{ApplicationState, ProtocolVersion}, reconnect::ReconnectError};
+
+const CONTROL_TIMEOUT: Duration = Duration::from_millis(300);
+/// Leeway for the loop to notice the deadline, on a busy machine.
+const SLACK: Duration = Duration::from_millis(700);
+
+#[test]
+fn host_names_are_resolved() {
+    let emulator = common::emulator(ProtocolVersion::Version3, None);
+    let mut config = common::network_config(emulator.tcp_port(), emulator.udp_port(), Duration::from_secs(1), None);
+    config.host = "localhost".to_owned();
+    let client = common::client(config);
+
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+    client.exit().unwrap();
+}
+
+#[test]
+fn lookups_are_given_up_after_the_control_timeout() {
+    // fails right away or hangs without a name server, either way it may not take longer than the timeout
+    let mut config = common::network_config(1, 0, CONTROL_TIMEOUT, Some(1));
+    config.host = "does-not-exist.invalid".to_owned();
+
+    let started = Instant::now();
+    let client = common::client(config);
+    let result = client.thread.join().unwrap();
+
+    assert!(matches!(result, Err(ReconnectError::AttemptsExhausted(1))), "{:?}", result);
+    assert!(started.elapsed() < CONTROL_TIMEOUT + SLACK, "gave up after {:?}", started.elapsed());
+}
+
+#[test]
+fn exiting_does_not_wait_for_a_lookup() {
+    let mut config = common::network_config(1, 0, Duration::from_secs(10), None);
+    config.host = "does-not-exist.invalid".to_owned();
+    let client = common::client(config);
+
+    let started = Instant::now();
+    client.exit().unwrap();
+    assert!(started.elapsed() < SLACK, "exited after {:?}", started.elapsed());
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use std::{net::{Ipv4Addr, TcpListener}, thread, time::{Duration, Instant}};
+
+use network_client::{emulator::Unresponsive, models::{ApplicationState, AttachData, AttachOutcome, ProtocolVersion, TcpMessage}, reconnect::ReconnectError};
+
+const CONTROL_TIMEOUT: Duration = Duration::from_millis(300);
+/// Leeway for the loop to notice the deadline, on a busy machine.
+const SLACK: Duration = Duration::from_millis(700);
+
+#[test]
+fn connecting_to_a_silent_server_times_out() {
+    // accepts every connection and keeps it open, but never sends a byte
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let mut streams = Vec::new();
+        for stream in listener.incoming() {
+            streams.push(stream);
+        }
+    });
+
+    let started = Instant::now();
+    let client = common::client(common::network_config(port, 0, CONTROL_TIMEOUT, Some(1)));
+    let result = client.thread.join().unwrap();
+
+    assert!(matches!(result, Err(ReconnectError::AttemptsExhausted(1))), "{:?}", result);
+    assert!(started.elapsed() < CONTROL_TIMEOUT + SLACK, "gave up after {:?}", started.elapsed());
+}
+
+#[test]
+fn attaching_to_a_silent_server_times_out() {
+    // completes the handshake, then never answers a request
+    let emulator = common::emulator(ProtocolVersion::Version3, Some(Unresponsive::Requests));
+    let client = common::client(common::network_config(emulator.tcp_port(), emulator.udp_port(), CONTROL_TIMEOUT, None));
+    assert!(common::wait_for(Duration::from_secs(5), || client.state.get() == ApplicationState::Connected));
+
+    let (state_sender, states) = flume::unbounded();
+    client.state.on_change(move |state| {
+        if state == ApplicationState::Disconnected {
+            let _ = state_sender.send(());
+        }
+    });
+
+    let (response, outcome) = flume::bounded(1);
+    let started = Instant::now();
+    client.network.send_tcp(TcpMessage::Attach(AttachData { handle: 1, vid: 0x057E, pid: 0x0306, response })).unwrap();
+
+    match outcome.recv_timeout(CONTROL_TIMEOUT + SLACK) {
+        Ok(AttachOutcome::Io(_)) => {},
+        Ok(_) => panic!("attach succeeded against a silent server"),
+        Err(e) => panic!("no outcome after {:?}: {}", started.elapsed(), e)
+    }
+    assert!(started.elapsed() >= CONTROL_TIMEOUT, "failed after only {:?}", started.elapsed());
+    // the timed out connection is given up on cleanly, so everything attaches again after reconnecting
+    let deadline = started + CONTROL_TIMEOUT + SLACK;
+    assert!(states.recv_deadline(deadline).is_ok(), "still connected after {:?}", started.elapsed());
+
+    client.exit().unwrap();
+}